@@ -0,0 +1,63 @@
+use std::sync::Arc;
+
+use atomic_websocket::{
+    client_sender::{ClientSenders, ClientSendersTrait},
+    common::make_atomic_message,
+    external::tokio::{self, runtime::Runtime, sync::RwLock},
+};
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+const PEERS: usize = 10_000;
+
+fn setup(rt: &Runtime) -> Arc<RwLock<ClientSenders>> {
+    rt.block_on(async {
+        let client_senders = Arc::new(RwLock::new(ClientSenders::new()));
+        for index in 0..PEERS {
+            let (sx, mut rx) = tokio::sync::mpsc::channel(8);
+            client_senders.add(&format!("peer-{index}"), sx).await;
+            tokio::spawn(async move { while rx.recv().await.is_some() {} });
+        }
+        client_senders
+    })
+}
+
+fn bench_client_senders(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let client_senders = setup(&rt);
+    let peers: Vec<String> = (0..PEERS).map(|index| format!("peer-{index}")).collect();
+    let message = make_atomic_message(100, vec![0; 64]);
+
+    let mut group = c.benchmark_group("client_senders_10k");
+    group.throughput(Throughput::Elements(PEERS as u64));
+    group.bench_function("send_each_peer_concurrently", |b| {
+        b.to_async(&rt).iter(|| async {
+            let tasks: Vec<_> = peers
+                .iter()
+                .cloned()
+                .map(|peer| {
+                    let client_senders = client_senders.clone();
+                    let message = message.clone();
+                    tokio::spawn(async move { client_senders.send(&peer, message).await })
+                })
+                .collect();
+            for task in tasks {
+                task.await.unwrap().unwrap();
+            }
+        })
+    });
+    group.bench_function("broadcast", |b| {
+        b.to_async(&rt)
+            .iter(|| async { client_senders.broadcast(message.clone()).await })
+    });
+    group.bench_function("is_active", |b| {
+        b.to_async(&rt).iter(|| async {
+            for peer in peers.iter() {
+                assert!(client_senders.is_active(peer).await);
+            }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_client_senders);
+criterion_main!(benches);
@@ -79,6 +79,12 @@ pub enum Category {
     ServerConnectInfo = 10007,
     ValidClient = 10008,
     ClientId = 10009,
+    RpcRequest = 10010,
+    RpcResponse = 10011,
+    Handshake = 10012,
+    Rejected = 10013,
+    Announce = 10014,
+    Probe = 10015,
 }
 
 impl ::core::convert::TryFrom<u32> for Category {
@@ -96,6 +102,12 @@ impl ::core::convert::TryFrom<u32> for Category {
             10007 => Ok(Category::ServerConnectInfo),
             10008 => Ok(Category::ValidClient),
             10009 => Ok(Category::ClientId),
+            10010 => Ok(Category::RpcRequest),
+            10011 => Ok(Category::RpcResponse),
+            10012 => Ok(Category::Handshake),
+            10013 => Ok(Category::Rejected),
+            10014 => Ok(Category::Announce),
+            10015 => Ok(Category::Probe),
             d => Err(::bebop::DeserializeError::InvalidEnumDiscriminator(
                 d.into(),
             )),
@@ -116,6 +128,12 @@ impl ::core::convert::From<Category> for u32 {
             Category::ServerConnectInfo => 10007,
             Category::ValidClient => 10008,
             Category::ClientId => 10009,
+            Category::RpcRequest => 10010,
+            Category::RpcResponse => 10011,
+            Category::Handshake => 10012,
+            Category::Rejected => 10013,
+            Category::Announce => 10014,
+            Category::Probe => 10015,
         }
     }
 }
@@ -444,6 +462,7 @@ pub enum SaveKey {
     ServerConnectInfo = 1,
     ValidClient = 2,
     ClientId = 3,
+    OuterUrl = 4,
 }
 
 impl ::core::convert::TryFrom<u32> for SaveKey {
@@ -454,6 +473,7 @@ impl ::core::convert::TryFrom<u32> for SaveKey {
             1 => Ok(SaveKey::ServerConnectInfo),
             2 => Ok(SaveKey::ValidClient),
             3 => Ok(SaveKey::ClientId),
+            4 => Ok(SaveKey::OuterUrl),
             d => Err(::bebop::DeserializeError::InvalidEnumDiscriminator(
                 d.into(),
             )),
@@ -467,6 +487,7 @@ impl ::core::convert::From<SaveKey> for u32 {
             SaveKey::ServerConnectInfo => 1,
             SaveKey::ValidClient => 2,
             SaveKey::ClientId => 3,
+            SaveKey::OuterUrl => 4,
         }
     }
 }
@@ -580,6 +601,252 @@ impl<'raw> ::bebop::SubRecord<'raw> for ValidClient {
 
 impl<'raw> ::bebop::Record<'raw> for ValidClient {}
 
+#[derive(Clone, Debug, PartialEq)]
+pub struct RpcRequest<'raw> {
+    pub id: u32,
+    pub category: u16,
+    pub datas: ::bebop::SliceWrapper<'raw, u8>,
+}
+
+impl<'raw> ::bebop::SubRecord<'raw> for RpcRequest<'raw> {
+    const MIN_SERIALIZED_SIZE: usize = <u32>::MIN_SERIALIZED_SIZE
+        + <u16>::MIN_SERIALIZED_SIZE
+        + <::bebop::SliceWrapper<'raw, u8>>::MIN_SERIALIZED_SIZE;
+
+    #[inline]
+    fn serialized_size(&self) -> usize {
+        self.id.serialized_size() + self.category.serialized_size() + self.datas.serialized_size()
+    }
+
+    ::bebop::define_serialize_chained!(Self => |zelf, dest| {
+        Ok(
+            zelf.id._serialize_chained(dest)? +
+            zelf.category._serialize_chained(dest)? +
+            zelf.datas._serialize_chained(dest)?
+        )
+    });
+
+    fn _deserialize_chained(raw: &'raw [u8]) -> ::bebop::DeResult<(usize, Self)> {
+        let mut i = 0;
+        if raw.len() - i < Self::MIN_SERIALIZED_SIZE {
+            let missing = Self::MIN_SERIALIZED_SIZE - (raw.len() - i);
+            return Err(::bebop::DeserializeError::MoreDataExpected(missing));
+        }
+
+        let (read, v0) = ::bebop::SubRecord::_deserialize_chained(&raw[i..])?;
+        i += read;
+        let (read, v1) = ::bebop::SubRecord::_deserialize_chained(&raw[i..])?;
+        i += read;
+        let (read, v2) = ::bebop::SubRecord::_deserialize_chained(&raw[i..])?;
+        i += read;
+
+        Ok((
+            i,
+            Self {
+                id: v0,
+                category: v1,
+                datas: v2,
+            },
+        ))
+    }
+}
+
+impl<'raw> ::bebop::Record<'raw> for RpcRequest<'raw> {}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RpcResponse<'raw> {
+    pub id: u32,
+    pub success: bool,
+    pub datas: ::bebop::SliceWrapper<'raw, u8>,
+}
+
+impl<'raw> ::bebop::SubRecord<'raw> for RpcResponse<'raw> {
+    const MIN_SERIALIZED_SIZE: usize = <u32>::MIN_SERIALIZED_SIZE
+        + <bool>::MIN_SERIALIZED_SIZE
+        + <::bebop::SliceWrapper<'raw, u8>>::MIN_SERIALIZED_SIZE;
+
+    #[inline]
+    fn serialized_size(&self) -> usize {
+        self.id.serialized_size() + self.success.serialized_size() + self.datas.serialized_size()
+    }
+
+    ::bebop::define_serialize_chained!(Self => |zelf, dest| {
+        Ok(
+            zelf.id._serialize_chained(dest)? +
+            zelf.success._serialize_chained(dest)? +
+            zelf.datas._serialize_chained(dest)?
+        )
+    });
+
+    fn _deserialize_chained(raw: &'raw [u8]) -> ::bebop::DeResult<(usize, Self)> {
+        let mut i = 0;
+        if raw.len() - i < Self::MIN_SERIALIZED_SIZE {
+            let missing = Self::MIN_SERIALIZED_SIZE - (raw.len() - i);
+            return Err(::bebop::DeserializeError::MoreDataExpected(missing));
+        }
+
+        let (read, v0) = ::bebop::SubRecord::_deserialize_chained(&raw[i..])?;
+        i += read;
+        let (read, v1) = ::bebop::SubRecord::_deserialize_chained(&raw[i..])?;
+        i += read;
+        let (read, v2) = ::bebop::SubRecord::_deserialize_chained(&raw[i..])?;
+        i += read;
+
+        Ok((
+            i,
+            Self {
+                id: v0,
+                success: v1,
+                datas: v2,
+            },
+        ))
+    }
+}
+
+impl<'raw> ::bebop::Record<'raw> for RpcResponse<'raw> {}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Handshake<'raw> {
+    pub peer: &'raw str,
+    pub credentials: ::bebop::SliceWrapper<'raw, u8>,
+}
+
+impl<'raw> ::bebop::SubRecord<'raw> for Handshake<'raw> {
+    const MIN_SERIALIZED_SIZE: usize =
+        <&'raw str>::MIN_SERIALIZED_SIZE + <::bebop::SliceWrapper<'raw, u8>>::MIN_SERIALIZED_SIZE;
+
+    #[inline]
+    fn serialized_size(&self) -> usize {
+        self.peer.serialized_size() + self.credentials.serialized_size()
+    }
+
+    ::bebop::define_serialize_chained!(Self => |zelf, dest| {
+        Ok(
+            zelf.peer._serialize_chained(dest)? +
+            zelf.credentials._serialize_chained(dest)?
+        )
+    });
+
+    fn _deserialize_chained(raw: &'raw [u8]) -> ::bebop::DeResult<(usize, Self)> {
+        let mut i = 0;
+        if raw.len() - i < Self::MIN_SERIALIZED_SIZE {
+            let missing = Self::MIN_SERIALIZED_SIZE - (raw.len() - i);
+            return Err(::bebop::DeserializeError::MoreDataExpected(missing));
+        }
+
+        let (read, v0) = ::bebop::SubRecord::_deserialize_chained(&raw[i..])?;
+        i += read;
+        let (read, v1) = ::bebop::SubRecord::_deserialize_chained(&raw[i..])?;
+        i += read;
+
+        Ok((
+            i,
+            Self {
+                peer: v0,
+                credentials: v1,
+            },
+        ))
+    }
+}
+
+impl<'raw> ::bebop::Record<'raw> for Handshake<'raw> {}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rejected<'raw> {
+    pub reason: &'raw str,
+}
+
+impl<'raw> ::bebop::SubRecord<'raw> for Rejected<'raw> {
+    const MIN_SERIALIZED_SIZE: usize = <&'raw str>::MIN_SERIALIZED_SIZE;
+
+    #[inline]
+    fn serialized_size(&self) -> usize {
+        self.reason.serialized_size()
+    }
+
+    ::bebop::define_serialize_chained!(Self => |zelf, dest| {
+        Ok(
+            zelf.reason._serialize_chained(dest)?
+        )
+    });
+
+    fn _deserialize_chained(raw: &'raw [u8]) -> ::bebop::DeResult<(usize, Self)> {
+        let mut i = 0;
+        if raw.len() - i < Self::MIN_SERIALIZED_SIZE {
+            let missing = Self::MIN_SERIALIZED_SIZE - (raw.len() - i);
+            return Err(::bebop::DeserializeError::MoreDataExpected(missing));
+        }
+
+        let (read, v0) = ::bebop::SubRecord::_deserialize_chained(&raw[i..])?;
+        i += read;
+
+        Ok((i, Self { reason: v0 }))
+    }
+}
+
+impl<'raw> ::bebop::Record<'raw> for Rejected<'raw> {}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Announce<'raw> {
+    pub server_id: &'raw str,
+    pub port: u16,
+    pub metadata: ::bebop::SliceWrapper<'raw, u8>,
+    pub use_tls: bool,
+}
+
+impl<'raw> ::bebop::SubRecord<'raw> for Announce<'raw> {
+    const MIN_SERIALIZED_SIZE: usize = <&'raw str>::MIN_SERIALIZED_SIZE
+        + <u16>::MIN_SERIALIZED_SIZE
+        + <::bebop::SliceWrapper<'raw, u8>>::MIN_SERIALIZED_SIZE
+        + <bool>::MIN_SERIALIZED_SIZE;
+
+    #[inline]
+    fn serialized_size(&self) -> usize {
+        self.server_id.serialized_size()
+            + self.port.serialized_size()
+            + self.metadata.serialized_size()
+            + self.use_tls.serialized_size()
+    }
+
+    ::bebop::define_serialize_chained!(Self => |zelf, dest| {
+        Ok(
+            zelf.server_id._serialize_chained(dest)? +
+            zelf.port._serialize_chained(dest)? +
+            zelf.metadata._serialize_chained(dest)? +
+            zelf.use_tls._serialize_chained(dest)?
+        )
+    });
+
+    fn _deserialize_chained(raw: &'raw [u8]) -> ::bebop::DeResult<(usize, Self)> {
+        let mut i = 0;
+        if raw.len() - i < Self::MIN_SERIALIZED_SIZE {
+            let missing = Self::MIN_SERIALIZED_SIZE - (raw.len() - i);
+            return Err(::bebop::DeserializeError::MoreDataExpected(missing));
+        }
+
+        let (read, v0) = ::bebop::SubRecord::_deserialize_chained(&raw[i..])?;
+        i += read;
+        let (read, v1) = ::bebop::SubRecord::_deserialize_chained(&raw[i..])?;
+        i += read;
+        let (read, v2) = ::bebop::SubRecord::_deserialize_chained(&raw[i..])?;
+        i += read;
+        let (read, v3) = ::bebop::SubRecord::_deserialize_chained(&raw[i..])?;
+        i += read;
+
+        Ok((
+            i,
+            Self {
+                server_id: v0,
+                port: v1,
+                metadata: v2,
+                use_tls: v3,
+            },
+        ))
+    }
+}
+
+impl<'raw> ::bebop::Record<'raw> for Announce<'raw> {}
+
 #[cfg(feature = "bebop-owned-all")]
 pub mod owned {
     #![allow(warnings)]
@@ -917,4 +1184,302 @@ pub mod owned {
     impl<'raw> ::bebop::Record<'raw> for ServerConnectInfo {}
 
     pub use super::ValidClient;
+
+    #[derive(Clone, Debug, PartialEq)]
+    pub struct RpcRequest {
+        pub id: u32,
+        pub category: u16,
+        pub datas: ::std::vec::Vec<u8>,
+    }
+
+    impl<'raw> ::core::convert::From<super::RpcRequest<'raw>> for RpcRequest {
+        fn from(value: super::RpcRequest) -> Self {
+            Self {
+                id: value.id,
+                category: value.category,
+                datas: value.datas.iter().map(|value| value).collect(),
+            }
+        }
+    }
+
+    impl<'raw> ::bebop::SubRecord<'raw> for RpcRequest {
+        const MIN_SERIALIZED_SIZE: usize = <u32>::MIN_SERIALIZED_SIZE
+            + <u16>::MIN_SERIALIZED_SIZE
+            + <::std::vec::Vec<u8>>::MIN_SERIALIZED_SIZE;
+
+        #[inline]
+        fn serialized_size(&self) -> usize {
+            self.id.serialized_size()
+                + self.category.serialized_size()
+                + self.datas.serialized_size()
+        }
+
+        ::bebop::define_serialize_chained!(Self => |zelf, dest| {
+            Ok(
+                zelf.id._serialize_chained(dest)? +
+                zelf.category._serialize_chained(dest)? +
+                zelf.datas._serialize_chained(dest)?
+            )
+        });
+
+        fn _deserialize_chained(raw: &'raw [u8]) -> ::bebop::DeResult<(usize, Self)> {
+            let mut i = 0;
+            if raw.len() - i < Self::MIN_SERIALIZED_SIZE {
+                let missing = Self::MIN_SERIALIZED_SIZE - (raw.len() - i);
+                return Err(::bebop::DeserializeError::MoreDataExpected(missing));
+            }
+
+            let (read, v0) = ::bebop::SubRecord::_deserialize_chained(&raw[i..])?;
+            i += read;
+            let (read, v1) = ::bebop::SubRecord::_deserialize_chained(&raw[i..])?;
+            i += read;
+            let (read, v2) = ::bebop::SubRecord::_deserialize_chained(&raw[i..])?;
+            i += read;
+
+            Ok((
+                i,
+                Self {
+                    id: v0,
+                    category: v1,
+                    datas: v2,
+                },
+            ))
+        }
+    }
+
+    impl<'raw> ::bebop::Record<'raw> for RpcRequest {}
+
+    #[derive(Clone, Debug, PartialEq)]
+    pub struct RpcResponse {
+        pub id: u32,
+        pub success: bool,
+        pub datas: ::std::vec::Vec<u8>,
+    }
+
+    impl<'raw> ::core::convert::From<super::RpcResponse<'raw>> for RpcResponse {
+        fn from(value: super::RpcResponse) -> Self {
+            Self {
+                id: value.id,
+                success: value.success,
+                datas: value.datas.iter().map(|value| value).collect(),
+            }
+        }
+    }
+
+    impl<'raw> ::bebop::SubRecord<'raw> for RpcResponse {
+        const MIN_SERIALIZED_SIZE: usize = <u32>::MIN_SERIALIZED_SIZE
+            + <bool>::MIN_SERIALIZED_SIZE
+            + <::std::vec::Vec<u8>>::MIN_SERIALIZED_SIZE;
+
+        #[inline]
+        fn serialized_size(&self) -> usize {
+            self.id.serialized_size()
+                + self.success.serialized_size()
+                + self.datas.serialized_size()
+        }
+
+        ::bebop::define_serialize_chained!(Self => |zelf, dest| {
+            Ok(
+                zelf.id._serialize_chained(dest)? +
+                zelf.success._serialize_chained(dest)? +
+                zelf.datas._serialize_chained(dest)?
+            )
+        });
+
+        fn _deserialize_chained(raw: &'raw [u8]) -> ::bebop::DeResult<(usize, Self)> {
+            let mut i = 0;
+            if raw.len() - i < Self::MIN_SERIALIZED_SIZE {
+                let missing = Self::MIN_SERIALIZED_SIZE - (raw.len() - i);
+                return Err(::bebop::DeserializeError::MoreDataExpected(missing));
+            }
+
+            let (read, v0) = ::bebop::SubRecord::_deserialize_chained(&raw[i..])?;
+            i += read;
+            let (read, v1) = ::bebop::SubRecord::_deserialize_chained(&raw[i..])?;
+            i += read;
+            let (read, v2) = ::bebop::SubRecord::_deserialize_chained(&raw[i..])?;
+            i += read;
+
+            Ok((
+                i,
+                Self {
+                    id: v0,
+                    success: v1,
+                    datas: v2,
+                },
+            ))
+        }
+    }
+
+    impl<'raw> ::bebop::Record<'raw> for RpcResponse {}
+
+    #[derive(Clone, Debug, PartialEq)]
+    pub struct Handshake {
+        pub peer: String,
+        pub credentials: ::std::vec::Vec<u8>,
+    }
+
+    impl<'raw> ::core::convert::From<super::Handshake<'raw>> for Handshake {
+        fn from(value: super::Handshake) -> Self {
+            Self {
+                peer: value.peer.into(),
+                credentials: value.credentials.iter().map(|value| value).collect(),
+            }
+        }
+    }
+
+    impl<'raw> ::bebop::SubRecord<'raw> for Handshake {
+        const MIN_SERIALIZED_SIZE: usize =
+            <String>::MIN_SERIALIZED_SIZE + <::std::vec::Vec<u8>>::MIN_SERIALIZED_SIZE;
+
+        #[inline]
+        fn serialized_size(&self) -> usize {
+            self.peer.serialized_size() + self.credentials.serialized_size()
+        }
+
+        ::bebop::define_serialize_chained!(Self => |zelf, dest| {
+            Ok(
+                zelf.peer._serialize_chained(dest)? +
+                zelf.credentials._serialize_chained(dest)?
+            )
+        });
+
+        fn _deserialize_chained(raw: &'raw [u8]) -> ::bebop::DeResult<(usize, Self)> {
+            let mut i = 0;
+            if raw.len() - i < Self::MIN_SERIALIZED_SIZE {
+                let missing = Self::MIN_SERIALIZED_SIZE - (raw.len() - i);
+                return Err(::bebop::DeserializeError::MoreDataExpected(missing));
+            }
+
+            let (read, v0) = ::bebop::SubRecord::_deserialize_chained(&raw[i..])?;
+            i += read;
+            let (read, v1) = ::bebop::SubRecord::_deserialize_chained(&raw[i..])?;
+            i += read;
+
+            Ok((
+                i,
+                Self {
+                    peer: v0,
+                    credentials: v1,
+                },
+            ))
+        }
+    }
+
+    impl<'raw> ::bebop::Record<'raw> for Handshake {}
+
+    #[derive(Clone, Debug, PartialEq)]
+    pub struct Rejected {
+        pub reason: String,
+    }
+
+    impl<'raw> ::core::convert::From<super::Rejected<'raw>> for Rejected {
+        fn from(value: super::Rejected) -> Self {
+            Self {
+                reason: value.reason.into(),
+            }
+        }
+    }
+
+    impl<'raw> ::bebop::SubRecord<'raw> for Rejected {
+        const MIN_SERIALIZED_SIZE: usize = <String>::MIN_SERIALIZED_SIZE;
+
+        #[inline]
+        fn serialized_size(&self) -> usize {
+            self.reason.serialized_size()
+        }
+
+        ::bebop::define_serialize_chained!(Self => |zelf, dest| {
+            Ok(
+                zelf.reason._serialize_chained(dest)?
+            )
+        });
+
+        fn _deserialize_chained(raw: &'raw [u8]) -> ::bebop::DeResult<(usize, Self)> {
+            let mut i = 0;
+            if raw.len() - i < Self::MIN_SERIALIZED_SIZE {
+                let missing = Self::MIN_SERIALIZED_SIZE - (raw.len() - i);
+                return Err(::bebop::DeserializeError::MoreDataExpected(missing));
+            }
+
+            let (read, v0) = ::bebop::SubRecord::_deserialize_chained(&raw[i..])?;
+            i += read;
+
+            Ok((i, Self { reason: v0 }))
+        }
+    }
+
+    impl<'raw> ::bebop::Record<'raw> for Rejected {}
+
+    #[derive(Clone, Debug, PartialEq)]
+    pub struct Announce {
+        pub server_id: String,
+        pub port: u16,
+        pub metadata: ::std::vec::Vec<u8>,
+        pub use_tls: bool,
+    }
+
+    impl<'raw> ::core::convert::From<super::Announce<'raw>> for Announce {
+        fn from(value: super::Announce) -> Self {
+            Self {
+                server_id: value.server_id.into(),
+                port: value.port,
+                metadata: value.metadata.iter().map(|value| value).collect(),
+                use_tls: value.use_tls,
+            }
+        }
+    }
+
+    impl<'raw> ::bebop::SubRecord<'raw> for Announce {
+        const MIN_SERIALIZED_SIZE: usize = <String>::MIN_SERIALIZED_SIZE
+            + <u16>::MIN_SERIALIZED_SIZE
+            + <::std::vec::Vec<u8>>::MIN_SERIALIZED_SIZE
+            + <bool>::MIN_SERIALIZED_SIZE;
+
+        #[inline]
+        fn serialized_size(&self) -> usize {
+            self.server_id.serialized_size()
+                + self.port.serialized_size()
+                + self.metadata.serialized_size()
+                + self.use_tls.serialized_size()
+        }
+
+        ::bebop::define_serialize_chained!(Self => |zelf, dest| {
+            Ok(
+                zelf.server_id._serialize_chained(dest)? +
+                zelf.port._serialize_chained(dest)? +
+                zelf.metadata._serialize_chained(dest)? +
+                zelf.use_tls._serialize_chained(dest)?
+            )
+        });
+
+        fn _deserialize_chained(raw: &'raw [u8]) -> ::bebop::DeResult<(usize, Self)> {
+            let mut i = 0;
+            if raw.len() - i < Self::MIN_SERIALIZED_SIZE {
+                let missing = Self::MIN_SERIALIZED_SIZE - (raw.len() - i);
+                return Err(::bebop::DeserializeError::MoreDataExpected(missing));
+            }
+
+            let (read, v0) = ::bebop::SubRecord::_deserialize_chained(&raw[i..])?;
+            i += read;
+            let (read, v1) = ::bebop::SubRecord::_deserialize_chained(&raw[i..])?;
+            i += read;
+            let (read, v2) = ::bebop::SubRecord::_deserialize_chained(&raw[i..])?;
+            i += read;
+            let (read, v3) = ::bebop::SubRecord::_deserialize_chained(&raw[i..])?;
+            i += read;
+
+            Ok((
+                i,
+                Self {
+                    server_id: v0,
+                    port: v1,
+                    metadata: v2,
+                    use_tls: v3,
+                },
+            ))
+        }
+    }
+
+    impl<'raw> ::bebop::Record<'raw> for Announce {}
 }
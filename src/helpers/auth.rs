@@ -0,0 +1,46 @@
+use std::{future::Future, net::SocketAddr, pin::Pin, sync::Arc};
+
+use super::upgrade::UpgradeRequest;
+
+type AuthFuture = Pin<Box<dyn Future<Output = AuthDecision> + Send>>;
+
+/// What a client presented before it is registered as a peer.
+#[derive(Debug, Clone)]
+pub struct HandshakeRequest {
+    /// The id the client claims in its first message.
+    pub peer: String,
+    /// Empty when the client sent a plain `Ping`.
+    pub credentials: Vec<u8>,
+    pub upgrade: UpgradeRequest,
+    pub addr: SocketAddr,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthDecision {
+    /// Registers the session under `peer`, which may differ from the claimed id.
+    Accept { peer: String },
+    /// Sends `Rejected` with `reason` and closes the session.
+    Reject { reason: String },
+}
+
+/// Decides whether a handshake is accepted and under which peer id.
+#[derive(Clone)]
+pub struct Authenticator {
+    handler: Arc<dyn Fn(HandshakeRequest) -> AuthFuture + Send + Sync>,
+}
+
+impl Authenticator {
+    pub fn new<F, Fut>(handler: F) -> Self
+    where
+        F: Fn(HandshakeRequest) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = AuthDecision> + Send + 'static,
+    {
+        Self {
+            handler: Arc::new(move |request| Box::pin(handler(request))),
+        }
+    }
+
+    pub async fn authenticate(&self, request: HandshakeRequest) -> AuthDecision {
+        (self.handler)(request).await
+    }
+}
@@ -0,0 +1,298 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use tokio::sync::{
+    mpsc::{self, error::TrySendError, Receiver, Sender},
+    Notify,
+};
+
+use crate::log_debug;
+
+use super::error::{AtomicWebsocketError, Result};
+
+/// What a sender does when the channel is full.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BackpressurePolicy {
+    /// Wait until the receiver makes room.
+    #[default]
+    Block,
+    /// Drop the oldest queued message to make room.
+    DropOldest,
+    /// Drop the message being sent.
+    DropNewest,
+    /// Close the connection the channel belongs to.
+    Disconnect,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChannelOptions {
+    pub capacity: usize,
+    pub policy: BackpressurePolicy,
+}
+
+impl ChannelOptions {
+    pub fn new(capacity: usize, policy: BackpressurePolicy) -> Self {
+        Self { capacity, policy }
+    }
+}
+
+/// Number of messages dropped because of a full channel.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DroppedMessages {
+    /// Client status or server event messages.
+    pub status: u64,
+    pub handle_message: u64,
+    pub outbound: u64,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct DropCounter(Arc<AtomicU64>);
+
+impl DropCounter {
+    fn add(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Drop counters of the channels one `ServerSender` or `ClientSenders` owns.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct DropCounters {
+    /// Client status or server event messages.
+    pub status: DropCounter,
+    pub handle_message: DropCounter,
+    pub outbound: DropCounter,
+}
+
+impl DropCounters {
+    pub(crate) fn dropped_messages(&self) -> DroppedMessages {
+        DroppedMessages {
+            status: self.status.get(),
+            handle_message: self.handle_message.get(),
+            outbound: self.outbound.get(),
+        }
+    }
+}
+
+pub fn channel<T: Send + 'static>(
+    options: ChannelOptions,
+    counter: DropCounter,
+) -> (PolicySender<T>, Receiver<T>) {
+    let (sx, rx) = mpsc::channel(options.capacity.max(1));
+    (PolicySender::new(sx, options, counter), rx)
+}
+
+/// An `mpsc::Sender` that applies a `BackpressurePolicy` and counts drops.
+pub struct PolicySender<T> {
+    sx: Sender<T>,
+    policy: BackpressurePolicy,
+    counter: DropCounter,
+    queue: Option<Arc<DropOldestQueue<T>>>,
+}
+
+impl<T> Clone for PolicySender<T> {
+    fn clone(&self) -> Self {
+        if let Some(queue) = &self.queue {
+            queue.senders.fetch_add(1, Ordering::AcqRel);
+        }
+        Self {
+            sx: self.sx.clone(),
+            policy: self.policy,
+            counter: self.counter.clone(),
+            queue: self.queue.clone(),
+        }
+    }
+}
+
+impl<T> Drop for PolicySender<T> {
+    fn drop(&mut self) {
+        if let Some(queue) = &self.queue {
+            // Counted explicitly, the pump can not tell from the `Arc` count
+            // whether this sender is already gone.
+            queue.senders.fetch_sub(1, Ordering::AcqRel);
+            queue.notify.notify_one();
+        }
+    }
+}
+
+impl<T: Send + 'static> PolicySender<T> {
+    pub fn new(sx: Sender<T>, options: ChannelOptions, counter: DropCounter) -> Self {
+        let queue = match options.policy {
+            BackpressurePolicy::DropOldest => Some(Arc::new(DropOldestQueue {
+                items: Mutex::new(VecDeque::new()),
+                capacity: options.capacity.max(1),
+                notify: Notify::new(),
+                is_started: AtomicBool::new(false),
+                senders: AtomicUsize::new(1),
+            })),
+            _ => None,
+        };
+        Self {
+            sx,
+            policy: options.policy,
+            counter,
+            queue,
+        }
+    }
+
+    /// Returns `ChannelFull` only with `BackpressurePolicy::Disconnect`.
+    pub async fn send(&self, value: T) -> Result<()> {
+        match self.policy {
+            BackpressurePolicy::Block => Ok(self.sx.send(value).await?),
+            BackpressurePolicy::DropOldest => self.push_oldest(value),
+            BackpressurePolicy::DropNewest | BackpressurePolicy::Disconnect => {
+                match self.sx.try_send(value) {
+                    Ok(_) => Ok(()),
+                    Err(TrySendError::Closed(_)) => Err(AtomicWebsocketError::ChannelClosed),
+                    Err(TrySendError::Full(_)) => {
+                        self.counter.add();
+                        log_debug!("Channel full, drop message");
+                        match self.policy {
+                            BackpressurePolicy::Disconnect => {
+                                Err(AtomicWebsocketError::ChannelFull)
+                            }
+                            _ => Ok(()),
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn sender(&self) -> &Sender<T> {
+        &self.sx
+    }
+
+    pub fn same_channel(&self, sx: &Sender<T>) -> bool {
+        self.sx.same_channel(sx)
+    }
+
+    fn push_oldest(&self, value: T) -> Result<()> {
+        if self.sx.is_closed() {
+            return Err(AtomicWebsocketError::ChannelClosed);
+        }
+        let Some(queue) = &self.queue else {
+            return Err(AtomicWebsocketError::ChannelClosed);
+        };
+        {
+            let mut items = queue.items.lock().unwrap_or_else(|e| e.into_inner());
+            if items.len() >= queue.capacity {
+                items.pop_front();
+                self.counter.add();
+                log_debug!("Channel full, drop oldest message");
+            }
+            items.push_back(value);
+        }
+        if !queue.is_started.swap(true, Ordering::AcqRel) {
+            tokio::spawn(pump(queue.clone(), self.sx.clone()));
+        }
+        queue.notify.notify_one();
+        Ok(())
+    }
+}
+
+struct DropOldestQueue<T> {
+    items: Mutex<VecDeque<T>>,
+    capacity: usize,
+    notify: Notify,
+    is_started: AtomicBool,
+    /// Live `PolicySender`s sharing this queue.
+    senders: AtomicUsize,
+}
+
+impl<T> DropOldestQueue<T> {
+    fn pop(&self) -> Option<T> {
+        self.items
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .pop_front()
+    }
+}
+
+/// Moves queued messages into the channel; ends once every sender is gone.
+async fn pump<T>(queue: Arc<DropOldestQueue<T>>, sx: Sender<T>) {
+    loop {
+        match queue.pop() {
+            Some(value) => {
+                if sx.send(value).await.is_err() {
+                    break;
+                }
+            }
+            None if queue.senders.load(Ordering::Acquire) == 0 => break,
+            None => queue.notify.notified().await,
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_backpressure_policies() {
+    let send_three = |policy| async move {
+        let counter = DropCounter::default();
+        let (sx, mut rx) = channel(ChannelOptions::new(2, policy), counter.clone());
+        let mut results = Vec::new();
+        for value in 1..=3 {
+            results.push(sx.send(value).await);
+        }
+        drop(sx);
+        let mut received = Vec::new();
+        while let Some(value) = rx.recv().await {
+            received.push(value);
+        }
+        (results, received, counter.get())
+    };
+
+    let (results, received, dropped) = send_three(BackpressurePolicy::DropNewest).await;
+    assert!(results.iter().all(|x| x.is_ok()));
+    assert_eq!((received, dropped), (vec![1, 2], 1));
+
+    let (results, received, dropped) = send_three(BackpressurePolicy::Disconnect).await;
+    assert_eq!(results[2], Err(AtomicWebsocketError::ChannelFull));
+    assert_eq!((received, dropped), (vec![1, 2], 1));
+
+    let counter = DropCounter::default();
+    let (sx, mut rx) = mpsc::channel(1);
+    let sx = PolicySender::new(
+        sx,
+        ChannelOptions::new(2, BackpressurePolicy::DropOldest),
+        counter.clone(),
+    );
+    sx.sender().send(0).await.unwrap();
+    for value in 1..=3 {
+        sx.send(value).await.unwrap();
+    }
+    drop(sx);
+    let mut received = Vec::new();
+    while let Some(value) = rx.recv().await {
+        received.push(value);
+    }
+    assert_eq!((received, counter.get()), (vec![0, 2, 3], 1));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_drop_oldest_closes_after_last_sender() {
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    for _ in 0..500 {
+        let (sx, mut rx) = channel(
+            ChannelOptions::new(4, BackpressurePolicy::DropOldest),
+            DropCounter::default(),
+        );
+        sx.send(1).await.unwrap();
+        // The pump has drained the queue and waits for the next message.
+        assert_eq!(rx.recv().await, Some(1));
+        drop(sx);
+        assert_eq!(
+            timeout(Duration::from_secs(1), rx.recv()).await.unwrap(),
+            None
+        );
+    }
+}
@@ -1,10 +1,19 @@
-use std::{sync::Arc, time::Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
 use async_trait::async_trait;
 use bebop::Record;
+use futures_util::future::join_all;
 use tokio::{
     sync::{
-        mpsc::{self, Receiver, Sender},
+        mpsc::{Receiver, Sender},
         RwLock,
     },
     time::sleep,
@@ -17,39 +26,133 @@ use crate::{
     schema::Data,
 };
 
-use super::{common::make_expired_output_message, traits::StringUtil};
+use super::{
+    channel::{
+        channel, BackpressurePolicy, ChannelOptions, DropCounters, DroppedMessages, PolicySender,
+    },
+    common::{make_expired_output_message, make_rpc_request_message, make_rpc_response_message},
+    error::{AtomicWebsocketError, Result},
+    rpc::{wait_response, PendingRequests},
+    traits::StringUtil,
+    upgrade::UpgradeRequest,
+};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum DisconnectReason {
+    /// The client sent `Disconnect` or closed the session.
+    ClientClosed,
+    /// The socket ended without `Disconnect`.
+    ConnectionClosed,
+    /// The server ended the session: shutdown, eviction or replacement.
+    ServerClosed,
+    /// Nothing was received from the other side within the timeout.
+    Timeout,
+    /// A channel was full with `BackpressurePolicy::Disconnect`.
+    Backpressure,
+    Error(AtomicWebsocketError),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ServerEvent {
+    Connected {
+        peer: String,
+        addr: SocketAddr,
+    },
+    /// A new session with the same id replaced the previous one.
+    Replaced {
+        peer: String,
+    },
+    Disconnected {
+        peer: String,
+        reason: DisconnectReason,
+    },
+    /// Removed for being idle; `Disconnected` follows once the socket closes.
+    Evicted {
+        peer: String,
+    },
+    /// The authenticator rejected the handshake, the session was never registered.
+    Rejected {
+        addr: SocketAddr,
+        reason: String,
+    },
+}
 
 pub struct ClientSenders {
-    lists: Vec<ClientSender>,
-    handle_message_sx: Sender<(Vec<u8>, String)>,
+    lists: HashMap<String, ClientSender>,
+    groups: HashMap<String, HashSet<String>>,
+    handle_message_sx: PolicySender<(Vec<u8>, String)>,
     handle_message_rx: Option<Receiver<(Vec<u8>, String)>>,
+    event_sx: PolicySender<ServerEvent>,
+    event_rx: Option<Receiver<ServerEvent>>,
+    outbound_channel: ChannelOptions,
+    dropped: DropCounters,
+    pending_requests: PendingRequests,
+}
+
+impl Default for ClientSenders {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ClientSenders {
     pub fn new() -> Self {
-        let (handle_message_sx, handle_message_rx) = mpsc::channel(1024);
+        Self::with_channels(
+            ChannelOptions::new(1024, BackpressurePolicy::Block),
+            ChannelOptions::new(64, BackpressurePolicy::DropNewest),
+        )
+    }
+
+    pub fn with_channels(
+        handle_message_channel: ChannelOptions,
+        event_channel: ChannelOptions,
+    ) -> Self {
+        let dropped = DropCounters::default();
+        let (handle_message_sx, handle_message_rx) =
+            channel(handle_message_channel, dropped.handle_message.clone());
+        let (event_sx, event_rx) = channel(event_channel, dropped.status.clone());
         Self {
-            lists: Vec::new(),
+            lists: HashMap::new(),
+            groups: HashMap::new(),
             handle_message_sx,
             handle_message_rx: Some(handle_message_rx),
+            event_sx,
+            event_rx: Some(event_rx),
+            outbound_channel: ChannelOptions::new(8, BackpressurePolicy::Block),
+            dropped,
+            pending_requests: PendingRequests::default(),
         }
     }
 
+    /// Policy for peers added afterwards.
+    pub fn set_outbound_channel(&mut self, outbound_channel: ChannelOptions) {
+        self.outbound_channel = outbound_channel;
+    }
+
+    pub fn dropped_messages(&self) -> DroppedMessages {
+        self.dropped.dropped_messages()
+    }
+
     pub async fn add(&mut self, peer: &str, sx: Sender<Message>) {
-        let list = self.lists.iter().position(|x| x.peer == peer);
-        log_debug!("Add peer: {:?}, list: {:?}", peer, list);
-        match list {
-            Some(index) => {
-                let list = self.lists.get_mut(index).unwrap();
-                let _ = list.sx.send(make_disconnect_message(&peer)).await;
-                list.sx = sx;
-            }
-            None => self.lists.push(ClientSender {
-                peer: peer.into(),
-                sx,
-                send_time: 0,
-            }),
-        };
+        if let Some(replaced) = self.add_session(peer, sx, None) {
+            notify_replaced(peer, replaced, &self.event_sx).await;
+        }
+    }
+
+    /// Swaps in the new session and returns the sender of the one it replaced,
+    /// so that one is notified without holding the registry lock.
+    pub(crate) fn add_session(
+        &mut self,
+        peer: &str,
+        sx: Sender<Message>,
+        upgrade: Option<UpgradeRequest>,
+    ) -> Option<Sender<Message>> {
+        log_debug!("Add peer: {:?}", peer);
+        let sx = PolicySender::new(sx, self.outbound_channel, self.dropped.outbound.clone());
+        let client = ClientSender::new(sx, upgrade.map(Arc::new));
+        self.lists
+            .insert(peer.into(), client)
+            .map(|client| client.sx.sender().clone())
     }
 
     pub fn get_handle_message_receiver(&mut self) -> Receiver<(Vec<u8>, String)> {
@@ -58,67 +161,126 @@ impl ClientSenders {
             .expect("Receiver already taken")
     }
 
-    pub async fn send_handle_message(&self, data: Vec<u8>, peer: &str) {
-        let handle_message_sx = self.handle_message_sx.clone();
-        let _ = handle_message_sx.send((data, peer.into())).await;
+    pub fn get_event_receiver(&mut self) -> Receiver<ServerEvent> {
+        self.event_rx.take().expect("Receiver already taken")
+    }
+
+    pub async fn send_event(&self, event: ServerEvent) {
+        let _ = self.event_sx.send(event).await;
     }
 
-    pub fn check_client_send_time(&mut self) {
+    pub async fn send_handle_message(&self, data: Vec<u8>, peer: &str) -> Result<()> {
+        self.handle_message_sx.send((data, peer.into())).await
+    }
+
+    /// Removes peers nothing was received from for `idle_timeout_seconds` and
+    /// returns their senders so they can be closed.
+    pub fn take_idle_clients(
+        &mut self,
+        idle_timeout_seconds: u64,
+    ) -> Vec<(String, Sender<Message>)> {
         let now = now().timestamp();
-        let mut remove_list = Vec::new();
-        for client in self.lists.iter() {
-            if client.send_time + 30 < now {
-                remove_list.push(client.peer.copy_string());
+        let remove_list: Vec<String> = self
+            .lists
+            .iter()
+            .filter(|(_, client)| client.received_time() + (idle_timeout_seconds as i64) < now)
+            .map(|(peer, _)| peer.copy_string())
+            .collect();
+        let mut idle_clients = Vec::new();
+        for peer in remove_list {
+            if let Some(client) = self.lists.remove(&peer) {
+                self.pending_requests.cancel_peer(&peer);
+                self.leave_all_groups(&peer);
+                idle_clients.push((peer, client.sx.sender().clone()));
             }
         }
-        self.lists.retain(|x| !remove_list.contains(&x.peer));
+        idle_clients
+    }
+
+    pub fn take_all(&mut self) -> Vec<(String, Sender<Message>)> {
+        self.pending_requests.cancel_all();
+        self.groups.clear();
+        self.lists
+            .drain()
+            .map(|(peer, client)| (peer, client.sx.sender().clone()))
+            .collect()
     }
 
     pub fn remove(&mut self, peer: &str) {
-        self.lists.retain(|x| x.peer != peer);
+        self.lists.remove(peer);
+        self.pending_requests.cancel_peer(peer);
+        self.leave_all_groups(peer);
         log_debug!("Remove peer: {:?}", peer);
     }
 
-    pub fn write_time(&mut self, peer: &str) {
-        for client in self.lists.iter_mut() {
-            if client.peer == peer {
-                client.write_time();
+    pub fn peers(&self) -> Vec<String> {
+        self.lists.keys().cloned().collect()
+    }
+
+    pub fn join_group(&mut self, group: &str, peer: &str) {
+        self.groups
+            .entry(group.into())
+            .or_default()
+            .insert(peer.into());
+    }
+
+    pub fn leave_group(&mut self, group: &str, peer: &str) {
+        if let Some(peers) = self.groups.get_mut(group) {
+            peers.remove(peer);
+            if peers.is_empty() {
+                self.groups.remove(group);
             }
         }
     }
 
-    pub async fn send(&self, peer: &str, message: Message) -> bool {
-        for client in self.lists.iter() {
-            if client.peer == peer {
-                let sender = client.sx.clone();
-                let mut backoff = Duration::from_millis(50); // 시작은 50ms로
-                let max_backoff = Duration::from_secs(1); // 최대 1초
-                let mut count = 0;
-
-                loop {
-                    match sender.send(message.clone()).await {
-                        Ok(_) => return true,
-                        Err(e) => {
-                            if count > 5 {
-                                log_error!("Failed to send after 5 retries: {:?}", e);
-                                return false;
-                            }
-
-                            log_error!("Error sending message (attempt {}): {:?}", count + 1, e);
-                            count += 1;
-
-                            // Exponential backoff with max limit
-                            backoff = std::cmp::min(backoff * 2, max_backoff);
-                            sleep(backoff).await;
-                        }
-                    }
-                }
+    pub fn group_peers(&self, group: &str) -> Vec<String> {
+        match self.groups.get(group) {
+            Some(peers) => peers.iter().cloned().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    fn leave_all_groups(&mut self, peer: &str) {
+        self.groups.retain(|_, peers| {
+            peers.remove(peer);
+            !peers.is_empty()
+        });
+    }
+
+    pub fn resolve_response(&mut self, peer: &str, datas: &[u8]) -> Result<()> {
+        self.pending_requests.resolve(peer, datas)
+    }
+
+    pub fn write_received_time(&self, peer: &str) {
+        if let Some(client) = self.lists.get(peer) {
+            client.write_received_time();
+        }
+    }
+
+    pub async fn send(&self, peer: &str, message: Message) -> Result<()> {
+        match self.lists.get(peer) {
+            Some(client) => send_with_retry(&client.sx, message).await,
+            None => Err(AtomicWebsocketError::PeerNotFound(peer.into())),
+        }
+    }
+
+    /// Removes `peer` only while it is still served by `sx`, so a peer that
+    /// reconnected in the meantime is kept.
+    pub(crate) fn remove_if_same(&mut self, peer: &str, sx: &Sender<Message>) {
+        if let Some(client) = self.lists.get(peer) {
+            if client.sx.same_channel(sx) {
+                self.remove(peer);
             }
         }
-        false
     }
+
     pub fn is_active(&self, peer: &str) -> bool {
-        self.lists.iter().any(|x| x.peer == peer)
+        self.lists.contains_key(peer)
+    }
+
+    /// The upgrade request of the current session of `peer`; `None` for peers added by hand.
+    pub fn upgrade_request(&self, peer: &str) -> Option<Arc<UpgradeRequest>> {
+        self.lists.get(peer)?.upgrade.clone()
     }
 }
 
@@ -126,59 +288,285 @@ impl ClientSenders {
 pub trait ClientSendersTrait {
     async fn add(&self, peer: &str, sx: Sender<Message>);
     async fn get_handle_message_receiver(&self) -> Receiver<(Vec<u8>, String)>;
-    async fn send_handle_message(&self, data: Data<'_>, peer: &str);
-    async fn send(&self, peer: &str, message: Message) -> bool;
+    async fn get_event_receiver(&self) -> Receiver<ServerEvent>;
+    async fn send_event(&self, event: ServerEvent);
+    async fn send_handle_message(&self, data: Data<'_>, peer: &str) -> Result<()>;
+    async fn send(&self, peer: &str, message: Message) -> Result<()>;
+    async fn request(
+        &self,
+        peer: &str,
+        category: u16,
+        datas: Vec<u8>,
+        timeout: Duration,
+    ) -> Result<Vec<u8>>;
+    async fn respond(&self, peer: &str, id: u32, datas: Vec<u8>) -> Result<()>;
     async fn expire_send(&self, peer_list: Vec<String>);
+    async fn broadcast(&self, message: Message);
+    async fn broadcast_except(&self, message: Message, exclude: &[String]);
+    async fn join_group(&self, group: &str, peer: &str);
+    async fn leave_group(&self, group: &str, peer: &str);
+    async fn send_to_group(&self, group: &str, message: Message);
     async fn is_active(&self, peer: &str) -> bool;
+    async fn upgrade_request(&self, peer: &str) -> Option<Arc<UpgradeRequest>>;
+    async fn disconnect_all(&self);
 }
 
 #[async_trait]
 impl ClientSendersTrait for Arc<RwLock<ClientSenders>> {
     async fn add(&self, peer: &str, sx: Sender<Message>) {
-        self.write().await.add(peer, sx).await;
+        register_session(self, peer, sx, None).await;
     }
 
     async fn get_handle_message_receiver(&self) -> Receiver<(Vec<u8>, String)> {
         self.write().await.get_handle_message_receiver()
     }
 
-    async fn send_handle_message(&self, data: Data<'_>, peer: &str) {
+    async fn get_event_receiver(&self) -> Receiver<ServerEvent> {
+        self.write().await.get_event_receiver()
+    }
+
+    async fn send_event(&self, event: ServerEvent) {
+        let event_sx = self.read().await.event_sx.clone();
+        let _ = event_sx.send(event).await;
+    }
+
+    async fn send_handle_message(&self, data: Data<'_>, peer: &str) -> Result<()> {
         let mut buf = Vec::new();
-        data.serialize(&mut buf).unwrap();
-        self.write().await.send_handle_message(buf, peer).await;
+        data.serialize(&mut buf)?;
+        let handle_message_sx = self.read().await.handle_message_sx.clone();
+        handle_message_sx.send((buf, peer.into())).await
     }
 
-    async fn send(&self, peer: &str, message: Message) -> bool {
-        let result = self.read().await.send(peer, message).await;
+    async fn send(&self, peer: &str, message: Message) -> Result<()> {
+        // The lock is only held for the lookup so slow peers never block others.
+        let client = match self.read().await.lists.get(peer) {
+            Some(client) => client.clone(),
+            None => return Err(AtomicWebsocketError::PeerNotFound(peer.into())),
+        };
+        let result = send_with_retry(&client.sx, message).await;
 
-        match result {
-            true => self.write().await.write_time(peer),
-            false => self.write().await.remove(peer),
+        if result.is_err() {
+            self.write().await.remove_if_same(peer, client.sx.sender());
         }
         result
     }
 
-    async fn expire_send(&self, peer_list: Vec<String>) {
-        for peer in self.read().await.lists.iter() {
-            if !peer_list.contains(&peer.peer) {
-                self.send(&peer.peer, make_expired_output_message()).await;
-            }
+    async fn request(
+        &self,
+        peer: &str,
+        category: u16,
+        datas: Vec<u8>,
+        timeout: Duration,
+    ) -> Result<Vec<u8>> {
+        let (id, rx) = self.write().await.pending_requests.register(peer);
+        let result = match self
+            .send(peer, make_rpc_request_message(id, category, &datas))
+            .await
+        {
+            Ok(_) => wait_response(rx, timeout).await,
+            Err(error) => Err(error),
+        };
+        if result.is_err() {
+            self.write().await.pending_requests.cancel(id);
         }
+        result
+    }
+
+    async fn respond(&self, peer: &str, id: u32, datas: Vec<u8>) -> Result<()> {
+        self.send(peer, make_rpc_response_message(id, &datas)).await
+    }
+
+    async fn expire_send(&self, peer_list: Vec<String>) {
+        self.broadcast_except(make_expired_output_message(), &peer_list)
+            .await;
+    }
+
+    async fn broadcast(&self, message: Message) {
+        self.broadcast_except(message, &[]).await;
+    }
+
+    async fn broadcast_except(&self, message: Message, exclude: &[String]) {
+        let mut peers = self.read().await.peers();
+        peers.retain(|peer| !exclude.contains(peer));
+        send_to_peers(self, peers, message).await;
+    }
+
+    async fn join_group(&self, group: &str, peer: &str) {
+        self.write().await.join_group(group, peer);
+    }
+
+    async fn leave_group(&self, group: &str, peer: &str) {
+        self.write().await.leave_group(group, peer);
+    }
+
+    async fn send_to_group(&self, group: &str, message: Message) {
+        let peers = self.read().await.group_peers(group);
+        send_to_peers(self, peers, message).await;
     }
     async fn is_active(&self, peer: &str) -> bool {
         self.read().await.is_active(peer)
     }
+
+    async fn upgrade_request(&self, peer: &str) -> Option<Arc<UpgradeRequest>> {
+        self.read().await.upgrade_request(peer)
+    }
+
+    async fn disconnect_all(&self) {
+        let clients = self.write().await.take_all();
+        join_all(clients.into_iter().map(|(peer, sx)| async move {
+            log_debug!("Disconnect peer: {:?}", peer);
+            let _ = sx.send(make_disconnect_message(&peer)).await;
+        }))
+        .await;
+    }
 }
 
-#[derive(Debug, Clone)]
-struct ClientSender {
-    peer: String,
+/// Adds a session like `ClientSenders::add`, notifying a replaced session
+/// after the registry lock is released.
+pub(crate) async fn register_session(
+    client_senders: &Arc<RwLock<ClientSenders>>,
+    peer: &str,
     sx: Sender<Message>,
-    send_time: i64,
+    upgrade: Option<UpgradeRequest>,
+) {
+    let (replaced, event_sx) = {
+        let mut client_senders = client_senders.write().await;
+        let replaced = client_senders.add_session(peer, sx, upgrade);
+        (replaced, client_senders.event_sx.clone())
+    };
+    if let Some(replaced) = replaced {
+        notify_replaced(peer, replaced, &event_sx).await;
+    }
+}
+
+/// A stalled replaced session is not waited for; it closes once its sender is dropped.
+async fn notify_replaced(
+    peer: &str,
+    replaced: Sender<Message>,
+    event_sx: &PolicySender<ServerEvent>,
+) {
+    let _ = replaced.try_send(make_disconnect_message(peer));
+    let _ = event_sx
+        .send(ServerEvent::Replaced { peer: peer.into() })
+        .await;
+}
+
+async fn send_with_retry(sender: &PolicySender<Message>, message: Message) -> Result<()> {
+    let mut backoff = Duration::from_millis(50); // 시작은 50ms로
+    let max_backoff = Duration::from_secs(1); // 최대 1초
+    let mut count = 0;
+
+    loop {
+        match sender.send(message.clone()).await {
+            Ok(_) => return Ok(()),
+            Err(AtomicWebsocketError::ChannelFull) => {
+                return Err(AtomicWebsocketError::ChannelFull)
+            }
+            Err(e) => {
+                if count > 5 {
+                    log_error!("Failed to send after 5 retries: {:?}", e);
+                    return Err(AtomicWebsocketError::ChannelClosed);
+                }
+
+                log_error!("Error sending message (attempt {}): {:?}", count + 1, e);
+                count += 1;
+
+                // Exponential backoff with max limit
+                backoff = std::cmp::min(backoff * 2, max_backoff);
+                sleep(backoff).await;
+            }
+        }
+    }
+}
+
+async fn send_to_peers(
+    client_senders: &Arc<RwLock<ClientSenders>>,
+    peers: Vec<String>,
+    message: Message,
+) {
+    let message = &message;
+    join_all(peers.iter().map(|peer| async move {
+        if let Err(error) = client_senders.send(peer, message.clone()).await {
+            log_error!("Failed to send to {}: {:?}", peer, error);
+        }
+    }))
+    .await;
+}
+
+#[derive(Clone)]
+struct ClientSender {
+    sx: PolicySender<Message>,
+    received_time: Arc<AtomicI64>,
+    upgrade: Option<Arc<UpgradeRequest>>,
 }
 
 impl ClientSender {
-    pub fn write_time(&mut self) {
-        self.send_time = now().timestamp();
+    fn new(sx: PolicySender<Message>, upgrade: Option<Arc<UpgradeRequest>>) -> Self {
+        Self {
+            sx,
+            received_time: Arc::new(AtomicI64::new(now().timestamp())),
+            upgrade,
+        }
     }
+
+    pub fn write_received_time(&self) {
+        self.received_time
+            .store(now().timestamp(), Ordering::Relaxed);
+    }
+
+    fn received_time(&self) -> i64 {
+        self.received_time.load(Ordering::Relaxed)
+    }
+}
+
+#[tokio::test]
+async fn test_broadcast_and_groups() {
+    use crate::helpers::common::make_pong_message;
+    use tokio::sync::mpsc;
+
+    let client_senders = Arc::new(RwLock::new(ClientSenders::new()));
+    let mut receivers = Vec::new();
+    for peer in ["a", "b", "c"] {
+        let (sx, rx) = mpsc::channel(8);
+        client_senders.add(peer, sx).await;
+        receivers.push(rx);
+    }
+    client_senders.join_group("room", "a").await;
+    client_senders.join_group("room", "b").await;
+
+    client_senders.broadcast(make_pong_message()).await;
+    client_senders
+        .broadcast_except(make_pong_message(), &["a".into()])
+        .await;
+    client_senders
+        .send_to_group("room", make_pong_message())
+        .await;
+    let counts: Vec<usize> = receivers.iter().map(|rx| rx.len()).collect();
+    assert_eq!(counts, vec![2, 3, 2]);
+
+    client_senders.write().await.remove("b");
+    assert_eq!(client_senders.read().await.group_peers("room"), vec!["a"]);
+    client_senders.leave_group("room", "a").await;
+    assert!(client_senders.read().await.groups.is_empty());
+}
+
+#[tokio::test]
+async fn test_replace_stalled_session() {
+    use crate::helpers::common::make_pong_message;
+    use tokio::{sync::mpsc, time::timeout};
+
+    let client_senders = Arc::new(RwLock::new(ClientSenders::new()));
+    let (stalled_sx, _stalled_rx) = mpsc::channel(1);
+    stalled_sx.send(make_pong_message()).await.unwrap();
+    client_senders.add("peer", stalled_sx).await;
+
+    let (sx, mut rx) = mpsc::channel(8);
+    timeout(Duration::from_secs(1), client_senders.add("peer", sx))
+        .await
+        .unwrap();
+    client_senders
+        .send("peer", make_pong_message())
+        .await
+        .unwrap();
+    assert!(rx.recv().await.is_some());
 }
@@ -1,4 +1,4 @@
-use std::{error::Error, sync::Arc};
+use std::sync::Arc;
 
 use bebop::Record;
 use native_db::Database;
@@ -6,11 +6,16 @@ use tokio::sync::RwLock;
 use tokio_tungstenite::tungstenite::{protocol::frame::Payload, Message};
 
 use crate::{
-    schema::{Category, Data, Disconnect, Expired, Ping},
+    schema::{
+        Category, Data, Disconnect, Expired, Handshake, Ping, Rejected, RpcRequest, RpcResponse,
+    },
     Settings,
 };
 
-use super::traits::StringUtil;
+use super::{
+    error::{AtomicWebsocketError, Result},
+    traits::StringUtil,
+};
 
 #[cfg(feature = "rinf")]
 #[cfg(not(feature = "debug"))]
@@ -81,17 +86,14 @@ macro_rules! log_error {
 pub async fn get_setting_by_key(
     db: Arc<RwLock<Database<'static>>>,
     key: String,
-) -> Result<Option<Settings>, Box<dyn Error>> {
+) -> Result<Option<Settings>> {
     let db = db.read().await;
     let reader = db.r_transaction()?;
 
     Ok(reader.get().primary::<Settings>(key)?)
 }
 
-pub async fn set_setting(
-    db: Arc<RwLock<Database<'static>>>,
-    settings: Settings,
-) -> Result<bool, Box<dyn Error>> {
+pub async fn set_setting(db: Arc<RwLock<Database<'static>>>, settings: Settings) -> Result<bool> {
     let db = db.read().await;
     let reader = db.r_transaction()?;
     let writer = db.rw_transaction()?;
@@ -114,6 +116,26 @@ pub async fn set_setting(
     Ok(true)
 }
 
+pub async fn remove_setting_by_key(
+    db: Arc<RwLock<Database<'static>>>,
+    key: String,
+) -> Result<bool> {
+    let db = db.read().await;
+    let reader = db.r_transaction()?;
+    let setting = reader.get().primary::<Settings>(key)?;
+    drop(reader);
+
+    match setting {
+        Some(setting) => {
+            let writer = db.rw_transaction()?;
+            writer.remove::<Settings>(setting)?;
+            writer.commit()?;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
 pub fn make_ping_message(peer: &str) -> Message {
     let mut datas = Vec::new();
     Ping {
@@ -125,9 +147,28 @@ pub fn make_ping_message(peer: &str) -> Message {
     make_response_message(Category::Ping, datas)
 }
 
-pub fn get_data_schema(data: &[u8]) -> Result<Data<'_>, Box<dyn Error>> {
+pub fn make_handshake_message(peer: &str, credentials: &[u8]) -> Message {
+    let mut datas = Vec::new();
+    Handshake {
+        peer,
+        credentials: bebop::SliceWrapper::from_raw(credentials),
+    }
+    .serialize(&mut datas)
+    .unwrap();
+    make_response_message(Category::Handshake, datas)
+}
+
+pub fn make_rejected_message(reason: &str) -> Message {
+    let mut datas = Vec::new();
+    Rejected { reason }.serialize(&mut datas).unwrap();
+    make_response_message(Category::Rejected, datas)
+}
+
+pub fn get_data_schema(data: &[u8]) -> Result<Data<'_>> {
     if data.len() < 2 {
-        return Err("Data length is too short".into());
+        return Err(AtomicWebsocketError::Codec(
+            "Data length is too short".into(),
+        ));
     }
     Ok(Data {
         category: data[0] as u16 + data[1] as u16 * 256,
@@ -135,14 +176,19 @@ pub fn get_data_schema(data: &[u8]) -> Result<Data<'_>, Box<dyn Error>> {
     })
 }
 
-pub fn make_atomic_message(category: u16, mut datas: Vec<u8>) -> Message {
+pub fn make_atomic_message(category: u16, datas: Vec<u8>) -> Message {
+    Message::Binary(Payload::Vec(make_atomic_bytes(category, datas)))
+}
+
+/// The `Data` framing of `make_atomic_message` without the WebSocket message.
+pub(crate) fn make_atomic_bytes(category: u16, mut datas: Vec<u8>) -> Vec<u8> {
     let mut byte = {
         let quotient = category / 256;
         let remainder = category % 256;
         vec![remainder as u8, quotient as u8]
     };
     byte.append(&mut datas);
-    Message::Binary(Payload::Vec(byte))
+    byte
 }
 
 pub fn make_response_message(category: Category, datas: Vec<u8>) -> Message {
@@ -159,6 +205,57 @@ pub fn make_pong_message() -> Message {
     make_response_message(Category::Pong, Vec::new())
 }
 
+pub fn make_rpc_request_message(id: u32, category: u16, datas: &[u8]) -> Message {
+    let mut buf = Vec::new();
+    RpcRequest {
+        id,
+        category,
+        datas: bebop::SliceWrapper::from_raw(datas),
+    }
+    .serialize(&mut buf)
+    .unwrap();
+    make_response_message(Category::RpcRequest, buf)
+}
+
+/// Decodes a handle message carrying an `RpcRequest` into its correlation id,
+/// inner category and payload. Answer it with `make_rpc_response_message` or
+/// `make_rpc_error_message` using the same id.
+pub fn get_rpc_request(message: &[u8]) -> Result<(u32, u16, Vec<u8>)> {
+    let data = Data::deserialize(message)?;
+    if data.category != Category::RpcRequest as u16 {
+        return Err(AtomicWebsocketError::Codec(format!(
+            "Not an rpc request: {}",
+            data.category
+        )));
+    }
+    let rpc = RpcRequest::deserialize(&data.datas)?;
+    Ok((rpc.id, rpc.category, rpc.datas.to_vec()))
+}
+
+pub fn make_rpc_response_message(id: u32, datas: &[u8]) -> Message {
+    let mut buf = Vec::new();
+    RpcResponse {
+        id,
+        success: true,
+        datas: bebop::SliceWrapper::from_raw(datas),
+    }
+    .serialize(&mut buf)
+    .unwrap();
+    make_response_message(Category::RpcResponse, buf)
+}
+
+pub fn make_rpc_error_message(id: u32, reason: &str) -> Message {
+    let mut buf = Vec::new();
+    RpcResponse {
+        id,
+        success: false,
+        datas: bebop::SliceWrapper::from_raw(reason.as_bytes()),
+    }
+    .serialize(&mut buf)
+    .unwrap();
+    make_response_message(Category::RpcResponse, buf)
+}
+
 pub fn make_expired_output_message() -> Message {
     let mut datas = Vec::new();
     Expired { is_expired: true }.serialize(&mut datas).unwrap();
@@ -0,0 +1,380 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use bebop::Record;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{
+    net::UdpSocket,
+    time::{timeout, MissedTickBehavior},
+};
+
+use crate::{
+    log_debug,
+    schema::{Announce, Category},
+};
+
+use super::{
+    common::{get_data_schema, make_atomic_bytes},
+    error::Result,
+    network::local_networks,
+};
+
+const MAX_DATAGRAM_SIZE: usize = 1500;
+
+/// Opt-in UDP discovery of the internal server, used before `ScanOptions`.
+#[derive(Clone, Debug)]
+pub struct DiscoveryOptions {
+    /// Multicast or broadcast address announcements are sent to. A unicast
+    /// address such as `127.0.0.1:port` only answers probes, e.g. in tests.
+    pub group: SocketAddr,
+    /// Seconds between announcements of the server and probes of the client.
+    pub interval_seconds: u64,
+    /// Announced by the server, a random id when empty. The client accepts
+    /// only this id, or any server when empty.
+    pub server_id: String,
+    /// Announced by the server next to its id and port.
+    pub metadata: Vec<u8>,
+    /// The client scans when nothing was announced within this; 0 waits until cancelled.
+    pub timeout_seconds: u64,
+}
+
+impl Default for DiscoveryOptions {
+    fn default() -> Self {
+        Self {
+            group: SocketAddr::from((Ipv4Addr::new(239, 255, 42, 99), 42424)),
+            interval_seconds: 2,
+            server_id: "".into(),
+            metadata: Vec::new(),
+            timeout_seconds: 6,
+        }
+    }
+}
+
+impl DiscoveryOptions {
+    fn accepts(&self, announcement: &Announcement) -> bool {
+        self.server_id.is_empty() || self.server_id == announcement.server_id
+    }
+}
+
+/// A server found through discovery.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Announcement {
+    pub server_id: String,
+    /// The address the announcement came from with the announced port.
+    pub addr: SocketAddr,
+    pub metadata: Vec<u8>,
+    /// Set when the server has a `tls_identity`.
+    pub use_tls: bool,
+}
+
+impl Announcement {
+    /// `wss://` when the server announced TLS, `ws://` otherwise.
+    pub fn url(&self) -> String {
+        match self.use_tls {
+            true => format!("wss://{}", self.addr),
+            false => format!("ws://{}", self.addr),
+        }
+    }
+
+    fn read(datagram: &[u8], from: SocketAddr) -> Option<Self> {
+        let data = get_data_schema(datagram).ok()?;
+        if data.category != Category::Announce as u16 {
+            return None;
+        }
+        let announce = Announce::deserialize(&data.datas).ok()?;
+        Some(Self {
+            server_id: announce.server_id.into(),
+            addr: SocketAddr::new(from.ip(), announce.port),
+            metadata: announce.metadata.to_vec(),
+            use_tls: announce.use_tls,
+        })
+    }
+}
+
+/// Announces the server on the group and answers probes.
+pub(crate) struct Announcer {
+    socket: UdpSocket,
+    group: SocketAddr,
+    interval: Duration,
+    announce: Vec<u8>,
+}
+
+impl Announcer {
+    pub(crate) fn bind(options: &DiscoveryOptions, port: u16, use_tls: bool) -> Result<Self> {
+        let server_id = match options.server_id.is_empty() {
+            true => nanoid::nanoid!(),
+            false => options.server_id.clone(),
+        };
+        let mut datas = Vec::new();
+        Announce {
+            server_id: &server_id,
+            port,
+            metadata: bebop::SliceWrapper::from_raw(&options.metadata),
+            use_tls,
+        }
+        .serialize(&mut datas)?;
+        Ok(Self {
+            socket: bind_group(options.group)?,
+            group: options.group,
+            interval: Duration::from_secs(options.interval_seconds.max(1)),
+            announce: make_atomic_bytes(Category::Announce as u16, datas),
+        })
+    }
+
+    pub(crate) async fn run(self) {
+        let mut interval = tokio::time::interval(self.interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut buf = [0u8; MAX_DATAGRAM_SIZE];
+
+        loop {
+            let target = tokio::select! {
+                _ = interval.tick() => self.group,
+                received = self.socket.recv_from(&mut buf) => match received {
+                    Ok((len, from)) if is_probe(&buf[..len]) => from,
+                    Ok(_) => continue,
+                    Err(error) => {
+                        log_debug!("Discovery receive failed: {:?}", error);
+                        continue;
+                    }
+                },
+            };
+            if let Err(error) = self.socket.send_to(&self.announce, target).await {
+                log_debug!("Failed to announce to {}: {:?}", target, error);
+            }
+        }
+    }
+}
+
+/// Probes `options.group` and waits for the first announcement of an
+/// accepted server. Fails with `Timeout` after `timeout_seconds`.
+pub async fn discover(options: &DiscoveryOptions) -> Result<Announcement> {
+    let probe = UdpSocket::bind(unspecified(options.group, 0)).await?;
+    if options.group.is_ipv4() {
+        probe.set_broadcast(true)?;
+    }
+    // Announcements to a shared group reach every listener, not only the prober.
+    let listener = match is_shared(options.group.ip()) {
+        true => Some(bind_group(options.group)?),
+        false => None,
+    };
+
+    let find = async {
+        let mut interval =
+            tokio::time::interval(Duration::from_secs(options.interval_seconds.max(1)));
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let probe_message = make_atomic_bytes(Category::Probe as u16, Vec::new());
+        let mut probe_buf = [0u8; MAX_DATAGRAM_SIZE];
+        let mut group_buf = [0u8; MAX_DATAGRAM_SIZE];
+
+        loop {
+            let received = tokio::select! {
+                _ = interval.tick() => {
+                    if let Err(error) = probe.send_to(&probe_message, options.group).await {
+                        log_debug!("Failed to probe {}: {:?}", options.group, error);
+                    }
+                    continue;
+                }
+                received = probe.recv_from(&mut probe_buf) => {
+                    received.map(|(len, from)| Announcement::read(&probe_buf[..len], from))
+                }
+                received = recv_from(listener.as_ref(), &mut group_buf) => {
+                    received.map(|(len, from)| Announcement::read(&group_buf[..len], from))
+                }
+            };
+            match received {
+                Ok(Some(announcement)) if options.accepts(&announcement) => break announcement,
+                Ok(_) => {}
+                Err(error) => log_debug!("Discovery receive failed: {:?}", error),
+            }
+        }
+    };
+    match options.timeout_seconds {
+        0 => Ok(find.await),
+        timeout_seconds => Ok(timeout(Duration::from_secs(timeout_seconds), find).await?),
+    }
+}
+
+async fn recv_from(socket: Option<&UdpSocket>, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+    match socket {
+        Some(socket) => socket.recv_from(buf).await,
+        None => std::future::pending().await,
+    }
+}
+
+fn is_probe(datagram: &[u8]) -> bool {
+    get_data_schema(datagram).is_ok_and(|data| data.category == Category::Probe as u16)
+}
+
+/// Multicast, limited broadcast or the broadcast address of a local network.
+fn is_shared(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            ip.is_multicast()
+                || ip.is_broadcast()
+                || local_networks()
+                    .iter()
+                    .any(|local| local.network.broadcast() == ip)
+        }
+        IpAddr::V6(ip) => ip.is_multicast(),
+    }
+}
+
+fn unspecified(group: SocketAddr, port: u16) -> SocketAddr {
+    match group {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)),
+    }
+}
+
+/// Binds the group port with address reuse, so servers and clients on the
+/// same host can share it, and joins the group when it is multicast.
+fn bind_group(group: SocketAddr) -> Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(group), Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&unspecified(group, group.port()).into())?;
+    match group.ip() {
+        IpAddr::V4(ip) => {
+            socket.set_broadcast(true)?;
+            if ip.is_multicast() {
+                socket.join_multicast_v4(&ip, &Ipv4Addr::UNSPECIFIED)?;
+            }
+        }
+        IpAddr::V6(ip) => {
+            if ip.is_multicast() {
+                socket.join_multicast_v6(&ip, 0)?;
+            }
+        }
+    }
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+#[cfg(test)]
+fn loopback_group() -> SocketAddr {
+    let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.local_addr().unwrap()
+}
+
+#[tokio::test]
+async fn test_discover_on_loopback() {
+    use crate::helpers::{
+        error::AtomicWebsocketError,
+        internal_server::{AtomicServer, ServerOptions},
+    };
+
+    let options = DiscoveryOptions {
+        group: loopback_group(),
+        interval_seconds: 1,
+        server_id: "lan".into(),
+        metadata: b"v1".to_vec(),
+        timeout_seconds: 3,
+    };
+    let server = AtomicServer::new(
+        "127.0.0.1:0",
+        ServerOptions {
+            discovery: Some(options.clone()),
+            ..Default::default()
+        },
+        None,
+    )
+    .await
+    .unwrap();
+
+    let announcement = discover(&DiscoveryOptions {
+        metadata: Vec::new(),
+        ..options.clone()
+    })
+    .await
+    .unwrap();
+    assert_eq!(
+        announcement,
+        Announcement {
+            server_id: "lan".into(),
+            addr: server.local_addr(),
+            metadata: b"v1".to_vec(),
+            use_tls: false,
+        }
+    );
+
+    let other = DiscoveryOptions {
+        server_id: "other".into(),
+        timeout_seconds: 1,
+        ..options
+    };
+    assert_eq!(discover(&other).await, Err(AtomicWebsocketError::Timeout));
+}
+
+#[tokio::test]
+async fn test_internal_client_discovery() {
+    use crate::{
+        helpers::{
+            internal_server::{AtomicServer, ServerOptions},
+            server_sender::SenderStatus,
+        },
+        schema::ServerConnectInfo,
+        test_db, AtomicWebsocket,
+    };
+
+    let options = DiscoveryOptions {
+        group: loopback_group(),
+        interval_seconds: 1,
+        timeout_seconds: 3,
+        ..Default::default()
+    };
+    let server = AtomicServer::new(
+        "127.0.0.1:0",
+        ServerOptions {
+            discovery: Some(options.clone()),
+            ..Default::default()
+        },
+        None,
+    )
+    .await
+    .unwrap();
+
+    let db = test_db();
+    let client = AtomicWebsocket::get_internal_client(
+        db.clone(),
+        super::internal_client::ClientOptions {
+            discovery: Some(options),
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap();
+    let mut status_receiver = client.get_status_receiver().await;
+    client
+        .get_internal_connect(
+            Some(ServerConnectInfo {
+                server_ip: "",
+                port: "1",
+            }),
+            db,
+        )
+        .await
+        .unwrap();
+
+    let mut statuses = Vec::new();
+    timeout(Duration::from_secs(5), async {
+        while let Some(status) = status_receiver.recv().await {
+            let is_connected = status == SenderStatus::Connected;
+            statuses.push(status);
+            if is_connected {
+                break;
+            }
+        }
+    })
+    .await
+    .unwrap();
+    let target = format!("ws://{}", server.local_addr());
+    assert!(statuses.contains(&SenderStatus::Discovering));
+    assert!(!statuses.contains(&SenderStatus::Scanning));
+    assert!(statuses.iter().any(|status| matches!(
+        status,
+        SenderStatus::Discovered(announcement) if announcement.url() == target
+    )));
+    assert!(statuses.contains(&SenderStatus::Connecting { target }));
+}
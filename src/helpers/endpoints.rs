@@ -0,0 +1,127 @@
+use crate::helpers::traits::date_time::now;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct EndpointHealth {
+    pub url: String,
+    /// Connect failures and pong timeouts since the endpoint last answered.
+    pub failures: u32,
+    /// Timestamp of the last successful upgrade.
+    pub last_connected: Option<i64>,
+}
+
+/// The ordered outer endpoints and which one is in use.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Endpoints {
+    health: Vec<EndpointHealth>,
+    current: usize,
+    pong_timeouts: u32,
+}
+
+impl Endpoints {
+    pub(crate) fn new(urls: Vec<String>) -> Self {
+        Self {
+            health: urls
+                .into_iter()
+                .map(|url| EndpointHealth {
+                    url,
+                    ..Default::default()
+                })
+                .collect(),
+            current: 0,
+            pong_timeouts: 0,
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.health.len()
+    }
+
+    pub(crate) fn current(&self) -> Option<String> {
+        self.health
+            .get(self.current)
+            .map(|health| health.url.clone())
+    }
+
+    pub(crate) fn health(&self) -> Vec<EndpointHealth> {
+        self.health.clone()
+    }
+
+    /// Starts from `url` when it is still one of the endpoints.
+    pub(crate) fn select(&mut self, url: &str) {
+        if let Some(index) = self.health.iter().position(|health| health.url == url) {
+            self.current = index;
+            self.pong_timeouts = 0;
+        }
+    }
+
+    pub(crate) fn connected(&mut self) {
+        if let Some(health) = self.health.get_mut(self.current) {
+            health.last_connected = Some(now().timestamp());
+        }
+    }
+
+    /// The current endpoint answered a ping.
+    pub(crate) fn alive(&mut self) {
+        if let Some(health) = self.health.get_mut(self.current) {
+            health.failures = 0;
+        }
+        self.pong_timeouts = 0;
+    }
+
+    /// Moves to the next endpoint, returns `(from, to)` when there is another one.
+    pub(crate) fn connect_failed(&mut self) -> Option<(String, String)> {
+        self.fail();
+        self.next()
+    }
+
+    /// Moves to the next endpoint after `limit` pong timeouts in a row.
+    pub(crate) fn pong_timeout(&mut self, limit: u32) -> Option<(String, String)> {
+        self.fail();
+        self.pong_timeouts += 1;
+        match self.pong_timeouts >= limit.max(1) {
+            true => self.next(),
+            false => None,
+        }
+    }
+
+    fn fail(&mut self) {
+        if let Some(health) = self.health.get_mut(self.current) {
+            health.failures += 1;
+        }
+    }
+
+    fn next(&mut self) -> Option<(String, String)> {
+        if self.health.len() < 2 {
+            return None;
+        }
+        let from = self.health[self.current].url.clone();
+        self.current = (self.current + 1) % self.health.len();
+        self.pong_timeouts = 0;
+        Some((from, self.health[self.current].url.clone()))
+    }
+}
+
+#[test]
+fn test_endpoint_failover() {
+    let mut endpoints = Endpoints::new(vec!["a".into(), "b".into()]);
+    assert_eq!(endpoints.current().as_deref(), Some("a"));
+
+    assert_eq!(endpoints.pong_timeout(2), None);
+    assert_eq!(endpoints.pong_timeout(2), Some(("a".into(), "b".into())));
+    endpoints.alive();
+    assert_eq!(endpoints.pong_timeout(2), None);
+    endpoints.alive();
+    assert_eq!(endpoints.connect_failed(), Some(("b".into(), "a".into())));
+
+    let health = endpoints.health();
+    assert_eq!((health[0].failures, health[1].failures), (2, 1));
+
+    endpoints.select("b");
+    assert_eq!(endpoints.current().as_deref(), Some("b"));
+    endpoints.select("gone");
+    assert_eq!(endpoints.current().as_deref(), Some("b"));
+
+    let mut single = Endpoints::new(vec!["a".into()]);
+    assert_eq!(single.connect_failed(), None);
+    assert_eq!(single.current().as_deref(), Some("a"));
+}
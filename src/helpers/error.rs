@@ -0,0 +1,132 @@
+use std::string::FromUtf8Error;
+
+use thiserror::Error;
+use tokio::{sync::mpsc::error::SendError, time::error::Elapsed};
+use tokio_tungstenite::tungstenite;
+
+pub type Result<T> = std::result::Result<T, AtomicWebsocketError>;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum AtomicWebsocketError {
+    #[error("database error: {0}")]
+    Database(String),
+    #[error("codec error: {0}")]
+    Codec(String),
+    #[error("handshake error: {0}")]
+    Handshake(String),
+    #[error("protocol error: {0}")]
+    Protocol(String),
+    #[error("io error: {0}")]
+    Io(String),
+    #[error("tls error: {0}")]
+    Tls(String),
+    #[error("operation timed out")]
+    Timeout,
+    #[error("channel closed")]
+    ChannelClosed,
+    #[error("channel full")]
+    ChannelFull,
+    #[error("not connected to server")]
+    NotConnected,
+    #[error("client closed")]
+    Closed,
+    #[error("expired by server")]
+    Expired,
+    #[error("rejected by server: {0}")]
+    Unauthorized(String),
+    #[error("peer not found: {0}")]
+    PeerNotFound(String),
+    #[error("remote error: {0}")]
+    Remote(String),
+}
+
+impl From<native_db::db_type::Error> for AtomicWebsocketError {
+    fn from(error: native_db::db_type::Error) -> Self {
+        Self::Database(error.to_string())
+    }
+}
+
+impl From<bebop::DeserializeError> for AtomicWebsocketError {
+    fn from(error: bebop::DeserializeError) -> Self {
+        Self::Codec(error.to_string())
+    }
+}
+
+impl From<bebop::SerializeError> for AtomicWebsocketError {
+    fn from(error: bebop::SerializeError) -> Self {
+        Self::Codec(error.to_string())
+    }
+}
+
+impl From<FromUtf8Error> for AtomicWebsocketError {
+    fn from(error: FromUtf8Error) -> Self {
+        Self::Codec(error.to_string())
+    }
+}
+
+impl From<std::io::Error> for AtomicWebsocketError {
+    fn from(error: std::io::Error) -> Self {
+        Self::Io(error.to_string())
+    }
+}
+
+impl From<Elapsed> for AtomicWebsocketError {
+    fn from(_: Elapsed) -> Self {
+        Self::Timeout
+    }
+}
+
+impl<T> From<SendError<T>> for AtomicWebsocketError {
+    fn from(_: SendError<T>) -> Self {
+        Self::ChannelClosed
+    }
+}
+
+impl From<tungstenite::Error> for AtomicWebsocketError {
+    fn from(error: tungstenite::Error) -> Self {
+        match error {
+            tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed => {
+                Self::ChannelClosed
+            }
+            tungstenite::Error::Io(error) => Self::Io(error.to_string()),
+            tungstenite::Error::Tls(error) => Self::Tls(error.to_string()),
+            tungstenite::Error::Url(_)
+            | tungstenite::Error::Http(_)
+            | tungstenite::Error::HttpFormat(_) => Self::Handshake(error.to_string()),
+            error => Self::Protocol(error.to_string()),
+        }
+    }
+}
+
+#[cfg(feature = "native_tls")]
+impl From<native_tls::Error> for AtomicWebsocketError {
+    fn from(error: native_tls::Error) -> Self {
+        Self::Tls(error.to_string())
+    }
+}
+
+#[cfg(feature = "rustls")]
+impl From<rustls::Error> for AtomicWebsocketError {
+    fn from(error: rustls::Error) -> Self {
+        Self::Tls(error.to_string())
+    }
+}
+
+#[cfg(feature = "rustls")]
+impl From<rustls::pki_types::pem::Error> for AtomicWebsocketError {
+    fn from(error: rustls::pki_types::pem::Error) -> Self {
+        Self::Tls(error.to_string())
+    }
+}
+
+#[test]
+fn test_tungstenite_error_mapping() {
+    assert_eq!(
+        AtomicWebsocketError::from(tungstenite::Error::ConnectionClosed),
+        AtomicWebsocketError::ChannelClosed
+    );
+    assert!(matches!(
+        AtomicWebsocketError::from(tungstenite::Error::Utf8),
+        AtomicWebsocketError::Protocol(_)
+    ));
+}
@@ -1,21 +1,29 @@
 use std::sync::{atomic::AtomicBool, Arc};
 
-use futures_util::{SinkExt, StreamExt};
+use bebop::Record;
+use futures_util::{stream::SplitStream, SinkExt, StreamExt};
 use native_db::Database;
 use std::time::Duration;
 use tokio::{
     net::TcpStream,
     sync::{mpsc, RwLock},
+    task::JoinSet,
     time::{sleep, timeout},
 };
 use tokio_tungstenite::{
-    connect_async, tungstenite::protocol::frame::Payload, MaybeTlsStream, WebSocketStream,
+    tungstenite::{client::IntoClientRequest, protocol::frame::Payload, Message},
+    MaybeTlsStream, WebSocketStream,
 };
 
 use crate::{
-    generated::schema::{Category, SaveKey},
+    generated::schema::{Category, Expired, Rejected, SaveKey},
     helpers::{
-        common::{get_data_schema, make_disconnect_message, make_ping_message},
+        client_sender::DisconnectReason,
+        common::{
+            get_data_schema, make_disconnect_message, make_handshake_message, make_ping_message,
+            remove_setting_by_key,
+        },
+        error::{AtomicWebsocketError, Result},
         server_sender::{SenderStatus, ServerSender, ServerSenderTrait},
         traits::{atomic::FlagAtomic, StringUtil},
     },
@@ -39,40 +47,55 @@ pub async fn wrap_get_internal_websocket(
     }
 }
 
+/// Connects in a task of `server_sender`, so closing the client stops it.
+pub(crate) async fn spawn_internal_websocket(
+    db: Arc<RwLock<Database<'static>>>,
+    server_sender: Arc<RwLock<ServerSender>>,
+    server_ip: String,
+    options: ClientOptions,
+) {
+    let connect = wrap_get_internal_websocket(db, server_sender.clone(), server_ip, options);
+    server_sender.write().await.spawn(async move {
+        connect.await;
+    });
+}
+
 pub async fn get_internal_websocket(
     db: Arc<RwLock<Database<'static>>>,
     server_sender: Arc<RwLock<ServerSender>>,
     server_ip: String,
     options: ClientOptions,
-) -> tokio_tungstenite::tungstenite::Result<()> {
+) -> Result<()> {
     log_debug!("Connecting to {}", server_ip);
-    match timeout(
+    server_sender
+        .send_status(SenderStatus::Connecting {
+            target: server_ip.copy_string(),
+        })
+        .await;
+    let error: AtomicWebsocketError = match timeout(
         Duration::from_secs(options.connect_timeout_seconds),
-        connect_async(&server_ip),
+        async {
+            options
+                .connect(server_ip.as_str().into_client_request()?)
+                .await
+        },
     )
     .await
     {
         Ok(Ok((ws_stream, _))) => {
-            handle_websocket(
-                db,
-                server_sender.clone(),
-                options,
-                server_ip.copy_string(),
-                ws_stream,
-            )
-            .await?
+            return handle_websocket(db, server_sender, options, server_ip, ws_stream).await;
         }
-        Err(e) => {
-            server_sender.remove_ip_if_valid_server_ip(&server_ip).await;
-            log_error!("Error connecting to {}: {:?}", server_ip, e);
-        }
-        Ok(Err(e)) => {
-            server_sender.remove_ip_if_valid_server_ip(&server_ip).await;
-            log_error!("Error connecting to {}: {:?}", server_ip, e);
-        }
-    }
-    log_debug!("Failed to server connect to {}", server_ip);
-    Ok(())
+        Err(e) => e.into(),
+        Ok(Err(e)) => e,
+    };
+    log_error!("Error connecting to {}: {:?}", server_ip, error);
+    server_sender
+        .send_status(SenderStatus::Error(error.clone()))
+        .await;
+    server_sender
+        .remove_ip_if_valid_server_ip(&server_ip)
+        .await?;
+    Err(error)
 }
 
 pub async fn handle_websocket(
@@ -81,117 +104,205 @@ pub async fn handle_websocket(
     options: ClientOptions,
     server_ip: String,
     ws_stream: WebSocketStream<MaybeTlsStream<TcpStream>>,
-) -> tokio_tungstenite::tungstenite::Result<()> {
-    let (mut ostream, mut istream) = ws_stream.split();
+) -> Result<()> {
+    let (mut ostream, istream) = ws_stream.split();
     log_debug!("Connected to {} for web socket", server_ip);
 
-    let (sx, mut rx) = mpsc::channel(8);
-    let id = get_id(db.clone()).await;
-    server_sender.add(sx.clone(), &server_ip).await;
+    let (sx, mut rx) = mpsc::channel(options.outbound_channel.capacity.max(1));
+    // Only the server sender keeps the channel alive, so dropping it closes the connection.
+    let weak_sx = sx.downgrade();
+    let id = get_id(db.clone()).await?;
+    match server_sender.add(sx, &server_ip).await {
+        Ok(_) => {}
+        Err(AtomicWebsocketError::Closed) => {
+            log_debug!("Client closed while connecting to {}", server_ip);
+            ostream.close().await?;
+            return Ok(());
+        }
+        Err(error) => {
+            log_error!("Failed to save server_connect_info {error:?}");
+            server_sender.send_status(SenderStatus::Error(error)).await;
+        }
+    }
 
-    if options.use_ping {
+    if !options.credentials.is_empty() {
+        log_debug!("Client send handshake");
+        server_sender
+            .send(make_handshake_message(&id, &options.credentials))
+            .await?;
+    } else if options.use_ping {
         log_debug!("Client send message: {:?}", make_ping_message(&id));
-        server_sender.send(make_ping_message(&id)).await;
+        server_sender.send(make_ping_message(&id)).await?;
     }
 
-    let retry_seconds = options.retry_seconds;
-    let mut is_first = true;
-    let server_sender_clone = server_sender.clone();
-    let server_ip_clone = server_ip.copy_string();
-    tokio::spawn(async move {
-        let server_ip = server_ip_clone;
-        let server_sender = server_sender_clone;
-        let is_wait_ping = Arc::new(AtomicBool::new(false));
-
-        while let Some(Ok(message)) = istream.next().await {
-            if let Payload::Owned(value) = message.into_data() {
-                let data = match get_data_schema(&value) {
-                    Ok(data) => data,
-                    Err(e) => {
-                        log_error!("Error getting data schema: {:?}", e);
-                        continue;
-                    }
-                };
+    // Held in a JoinSet so the reader is aborted together with this connection.
+    let mut reader = JoinSet::new();
+    reader.spawn(read_messages(istream, server_sender.clone(), options, id));
 
-                let id = id.copy_string();
-                log_debug!("Client receive message: {:?}", data);
-                if data.category == Category::Pong as u16 {
-                    if is_first {
-                        is_first = false;
-                        server_sender.send_status(SenderStatus::Connected).await;
-                    }
-                    if !is_wait_ping.is_true() {
-                        is_wait_ping.set_bool(true);
-                        server_sender.write_received_times().await;
-                        let server_sender_clone = server_sender.clone();
-                        let is_wait_ping_clone = is_wait_ping.clone();
-                        tokio::spawn(async move {
-                            sleep(Duration::from_secs(retry_seconds)).await;
-                            server_sender_clone.send(make_ping_message(&id)).await;
-                            is_wait_ping_clone.set_bool(false);
-                        });
-                    }
-                    continue;
-                } else if data.category == Category::Disconnect as u16 {
-                    let _ = sx
-                        .send(make_disconnect_message(
-                            server_ip
-                                .split("://")
-                                .nth(1)
-                                .unwrap()
-                                .split(":")
-                                .nth(0)
-                                .unwrap(),
-                        ))
-                        .await;
-                    break;
+    let reason = loop {
+        tokio::select! {
+            message = rx.recv() => {
+                let Some(message) = message else {
+                    break DisconnectReason::ClientClosed;
+                };
+                if let Err(e) = ostream.send(message.clone()).await {
+                    log_error!("Error sending message: {:?}", e);
+                    break DisconnectReason::Error(e.into());
                 }
-                server_sender.send_handle_message(data).await;
-            }
-        }
-    });
-
-    while let Some(message) = rx.recv().await {
-        match ostream.send(message.clone()).await {
-            Ok(_) => {
-                if let Payload::Owned(data) = message.into_data() {
-                    let data = match get_data_schema(&data) {
+                if let Message::Binary(data) = message {
+                    let data = match get_data_schema(data.as_slice()) {
                         Ok(data) => data,
                         Err(e) => {
                             log_error!("Error getting data schema: {:?}", e);
-                            rx.close();
-                            break;
+                            break DisconnectReason::Error(e);
                         }
                     };
                     log_debug!("Send message: {:?}", data);
                     if data.category == Category::Disconnect as u16 {
-                        rx.close();
-                        break;
+                        break DisconnectReason::ClientClosed;
                     }
                 }
             }
-            Err(e) => {
-                log_error!("Error sending message: {:?}", e);
-                break;
+            Some(result) = reader.join_next() => {
+                let reason = result.unwrap_or(DisconnectReason::ConnectionClosed);
+                if reason == DisconnectReason::ServerClosed {
+                    let host = server_ip.split("://").nth(1).unwrap_or(&server_ip);
+                    let host = host.split(':').next().unwrap_or(host);
+                    let _ = ostream.send(make_disconnect_message(host)).await;
+                }
+                break reason;
             }
         }
+    };
+    log_debug!("WebSocket closed: {:?}", reason);
+    rx.close();
+    reader.abort_all();
+    // A session dropped by the server sender was already reported by whoever dropped it.
+    let is_current = match weak_sx.upgrade() {
+        Some(sx) => server_sender.write().await.remove_if_same(&sx),
+        None => false,
+    };
+    if is_current {
+        server_sender
+            .send_status(SenderStatus::Disconnected { reason })
+            .await;
     }
-    log_debug!("WebSocket closed");
-    ostream.flush().await?;
+    ostream.close().await?;
     Ok(())
 }
 
-pub async fn get_id(db: Arc<RwLock<Database<'static>>>) -> String {
+async fn read_messages(
+    mut istream: SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>,
+    server_sender: Arc<RwLock<ServerSender>>,
+    options: ClientOptions,
+    id: String,
+) -> DisconnectReason {
+    let ping_interval_seconds = options.ping_interval_seconds;
+    let mut is_first = true;
+    let is_wait_ping = Arc::new(AtomicBool::new(false));
+
+    loop {
+        let message = match istream.next().await {
+            Some(Ok(message)) => message,
+            Some(Err(e)) => return DisconnectReason::Error(e.into()),
+            None => return DisconnectReason::ConnectionClosed,
+        };
+        if let Payload::Owned(value) = message.into_data() {
+            let data = match get_data_schema(&value) {
+                Ok(data) => data,
+                Err(e) => {
+                    log_error!("Error getting data schema: {:?}", e);
+                    continue;
+                }
+            };
+
+            let id = id.copy_string();
+            log_debug!("Client receive message: {:?}", data);
+            if data.category == Category::Pong as u16 {
+                if is_first {
+                    is_first = false;
+                    let mut clone = server_sender.write().await;
+                    clone.reset_reconnect_attempt();
+                    clone.endpoints.alive();
+                    drop(clone);
+                    server_sender.send_status(SenderStatus::Connected).await;
+                }
+                if !is_wait_ping.is_true() {
+                    is_wait_ping.set_bool(true);
+                    server_sender.write_received_times().await;
+                    let server_sender_clone = server_sender.clone();
+                    let is_wait_ping_clone = is_wait_ping.clone();
+                    server_sender.write().await.spawn(async move {
+                        sleep(Duration::from_secs(ping_interval_seconds)).await;
+                        if let Err(error) = server_sender_clone.send(make_ping_message(&id)).await {
+                            log_error!("Error sending ping: {:?}", error);
+                        }
+                        is_wait_ping_clone.set_bool(false);
+                    });
+                }
+                continue;
+            } else if data.category == Category::RpcResponse as u16 {
+                if let Err(error) = server_sender.write().await.resolve_response(&data.datas) {
+                    log_error!("Error resolving response: {:?}", error);
+                }
+                continue;
+            } else if data.category == Category::Disconnect as u16 {
+                return DisconnectReason::ServerClosed;
+            } else if data.category == Category::Rejected as u16 {
+                let reason = match Rejected::deserialize(&data.datas) {
+                    Ok(rejected) => rejected.reason.into(),
+                    Err(e) => e.to_string(),
+                };
+                return DisconnectReason::Error(AtomicWebsocketError::Unauthorized(reason));
+            } else if data.category == Category::Expired as u16 {
+                match Expired::deserialize(&data.datas) {
+                    Ok(expired) if expired.is_expired => {
+                        expire(&server_sender, &options).await;
+                        return DisconnectReason::ServerClosed;
+                    }
+                    Ok(_) => {}
+                    Err(e) => log_error!("Error getting expired: {:?}", e),
+                }
+                continue;
+            }
+            match server_sender.send_handle_message(data).await {
+                Ok(_) => {}
+                Err(AtomicWebsocketError::ChannelFull) => {
+                    log_error!("Handle message channel full, disconnect");
+                    return DisconnectReason::Backpressure;
+                }
+                Err(error) => log_error!("Error handling message: {:?}", error),
+            }
+        }
+    }
+}
+
+async fn expire(server_sender: &Arc<RwLock<ServerSender>>, options: &ClientOptions) {
+    let mut clone = server_sender.write().await;
+    clone.is_expired = true;
+    let db = clone.db.clone();
+    drop(clone);
+    server_sender.send_status(SenderStatus::Expired).await;
+    if options.clear_connect_info_on_expired {
+        if let Err(error) =
+            remove_setting_by_key(db, format!("{:?}", SaveKey::ServerConnectInfo)).await
+        {
+            log_error!("Failed to clear server_connect_info {error:?}");
+            server_sender.send_status(SenderStatus::Error(error)).await;
+        }
+    }
+}
+
+pub async fn get_id(db: Arc<RwLock<Database<'static>>>) -> Result<String> {
     let db = db.read().await;
-    let reader = db.r_transaction().unwrap();
+    let reader = db.r_transaction()?;
 
     let mut return_string = String::new();
     if let Some(data) = reader
         .get()
-        .primary::<Settings>(format!("{:?}", SaveKey::ClientId))
-        .unwrap()
+        .primary::<Settings>(format!("{:?}", SaveKey::ClientId))?
     {
-        return_string = String::from_utf8(data.value).unwrap().into()
+        return_string = String::from_utf8(data.value)?
     }
-    return_string
+    Ok(return_string)
 }
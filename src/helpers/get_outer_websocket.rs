@@ -1,18 +1,22 @@
 use std::sync::Arc;
 
-use crate::{helpers::get_internal_websocket::handle_websocket, log_error};
+use crate::{
+    generated::schema::SaveKey,
+    helpers::{
+        common::set_setting,
+        error::{AtomicWebsocketError, Result},
+        get_internal_websocket::handle_websocket,
+    },
+    log_error, Settings,
+};
 use native_db::Database;
-#[cfg(feature = "native_tls")]
-use native_tls::TlsConnector;
 use tokio::sync::RwLock;
 
-#[cfg(not(feature = "native_tls"))]
-use tokio_tungstenite::connect_async;
-#[cfg(feature = "native_tls")]
-use tokio_tungstenite::{connect_async_tls_with_config, Connector};
-
 use crate::{
-    helpers::{server_sender::ServerSender, traits::StringUtil},
+    helpers::{
+        server_sender::{SenderStatus, ServerSender, ServerSenderTrait, UpgradeResponse},
+        traits::{atomic::FlagAtomic, StringUtil},
+    },
     server_sender::ClientOptions,
 };
 use std::time::Duration;
@@ -33,68 +37,71 @@ pub async fn wrap_get_outer_websocket(
     }
 }
 
-#[cfg(feature = "native_tls")]
 pub async fn get_outer_websocket(
     db: Arc<RwLock<Database<'static>>>,
     server_sender: Arc<RwLock<ServerSender>>,
     options: ClientOptions,
-) -> tokio_tungstenite::tungstenite::Result<()> {
-    let server_ip = format!("wss://{}", &options.url);
+) -> Result<()> {
+    // Every endpoint is tried at most once per call.
+    let attempts = server_sender.read().await.endpoints.len();
+    let mut error = AtomicWebsocketError::NotConnected;
+    for _ in 0..attempts {
+        if server_sender.read().await.is_closed.is_true() {
+            return Err(AtomicWebsocketError::Closed);
+        }
+        let Some(url) = server_sender.read().await.endpoints.current() else {
+            break;
+        };
+        let server_ip = options.outer_url(&url);
 
-    let connector = TlsConnector::new().expect("Failed to create TLS connector");
-    let connector = Connector::NativeTls(connector);
+        log_debug!("Connecting to WebSocket server: {:?}", &server_ip);
+        server_sender
+            .send_status(SenderStatus::Connecting {
+                target: server_ip.copy_string(),
+            })
+            .await;
+        error = match timeout(
+            Duration::from_secs(options.connect_timeout_seconds),
+            async { options.connect(options.outer_request(&url)?).await },
+        )
+        .await
+        {
+            Ok(Ok((ws_stream, response))) => {
+                let mut clone = server_sender.write().await;
+                clone.upgrade_response = Some(UpgradeResponse::from(&response));
+                clone.endpoints.connected();
+                drop(clone);
+                if let Err(error) = remember_endpoint(db.clone(), &url).await {
+                    log_error!("Failed to save outer url {error:?}");
+                }
+                return handle_websocket(db, server_sender, options, server_ip, ws_stream).await;
+            }
+            Err(e) => e.into(),
+            Ok(Err(e)) => e,
+        };
+        log_debug!("Failed to server connect to {}: {:?}", server_ip, error);
+        server_sender
+            .send_status(SenderStatus::Error(error.clone()))
+            .await;
 
-    log_debug!("Connecting to WebSocket server: {:?}", &server_ip);
-    match timeout(
-        Duration::from_secs(options.connect_timeout_seconds),
-        connect_async_tls_with_config(&server_ip, None, false, Some(connector)),
-    )
-    .await
-    {
-        Ok(Ok((ws_stream, _))) => {
-            handle_websocket(
-                db,
-                server_sender.clone(),
-                options,
-                server_ip.copy_string(),
-                ws_stream,
-            )
-            .await?;
-        }
-        _ => {}
+        let failover = server_sender.write().await.endpoints.connect_failed();
+        let Some((from, to)) = failover else {
+            break;
+        };
+        server_sender
+            .send_status(SenderStatus::Failover { from, to })
+            .await;
     }
-    log_debug!("Failed to server connect to {}", server_ip);
-
-    Ok(())
+    Err(error)
 }
 
-#[cfg(not(feature = "native_tls"))]
-pub async fn get_outer_websocket(
-    db: Arc<RwLock<Database<'static>>>,
-    server_sender: Arc<RwLock<ServerSender>>,
-    options: ClientOptions,
-) -> tokio_tungstenite::tungstenite::Result<()> {
-    let server_ip = format!("ws://{}", &options.url);
-    log_debug!("Connecting to WebSocket server: {:?}", &server_ip);
-    match timeout(
-        Duration::from_secs(options.connect_timeout_seconds),
-        connect_async(&server_ip),
+async fn remember_endpoint(db: Arc<RwLock<Database<'static>>>, url: &str) -> Result<bool> {
+    set_setting(
+        db,
+        Settings {
+            key: format!("{:?}", SaveKey::OuterUrl),
+            value: url.as_bytes().to_vec(),
+        },
     )
     .await
-    {
-        Ok(Ok((ws_stream, _))) => {
-            handle_websocket(
-                db,
-                server_sender.clone(),
-                options,
-                server_ip.copy_string(),
-                ws_stream,
-            )
-            .await?
-        }
-        _ => {}
-    }
-    log_debug!("Failed to server connect to {}", server_ip);
-
-    Ok(())
 }
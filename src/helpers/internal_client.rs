@@ -1,34 +1,86 @@
-use std::error::Error;
-use std::net::UdpSocket;
+use std::sync::atomic::AtomicBool;
+use std::sync::Mutex;
 use std::{sync::Arc, time::Duration};
 
 use crate::generated::schema::{SaveKey, ServerConnectInfo};
+use crate::helpers::discovery::{discover, Announcement, DiscoveryOptions};
 use crate::helpers::get_internal_websocket::handle_websocket;
 use crate::helpers::get_outer_websocket::wrap_get_outer_websocket;
-use crate::helpers::scan_manager::ScanManager;
+use crate::helpers::network::local_ip_address;
+use crate::helpers::scan_manager::{ScanCancel, ScanManager, ScanOptions};
+#[cfg(any(feature = "native_tls", feature = "rustls"))]
+use crate::helpers::tls::ClientTlsOptions;
 use crate::helpers::{
+    channel::{BackpressurePolicy, ChannelOptions, DroppedMessages},
+    client_sender::DisconnectReason,
     common::{get_setting_by_key, make_ping_message},
-    get_internal_websocket::{get_id, wrap_get_internal_websocket},
-    server_sender::{SenderStatus, ServerSender, ServerSenderTrait},
-    traits::date_time::now,
+    endpoints::EndpointHealth,
+    error::{AtomicWebsocketError, Result},
+    get_internal_websocket::{get_id, spawn_internal_websocket},
+    router::Router,
+    server_sender::{SenderStatus, ServerSender, ServerSenderTrait, UpgradeResponse},
+    traits::{atomic::FlagAtomic, date_time::now},
 };
 use crate::{log_debug, log_error, Settings};
 use bebop::Record;
 use native_db::Database;
 
+use tokio::net::TcpStream;
 use tokio::sync::mpsc::Receiver;
 use tokio::sync::RwLock;
-use tokio::time::{Instant, MissedTickBehavior};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, Instant, MissedTickBehavior};
+#[cfg(any(feature = "native_tls", feature = "rustls"))]
+use tokio_tungstenite::client_async_with_config;
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::{
+        client::IntoClientRequest,
+        handshake::client::{Request, Response},
+        http::{header::SEC_WEBSOCKET_PROTOCOL, HeaderMap, HeaderValue},
+    },
+    MaybeTlsStream, WebSocketStream,
+};
 
 #[derive(Clone)]
 pub struct ClientOptions {
     pub use_ping: bool,
+    /// Outer server address, either a full `ws://`/`wss://` URL or `host:port`.
+    /// Without a scheme, `wss://` is used when `use_tls` is set and `ws://` otherwise.
     pub url: String,
+    /// Tried in order after `url`; the one that last worked is tried first next time.
+    pub fallback_urls: Vec<String>,
+    /// Pong timeouts in a row before the next endpoint is tried.
+    pub failover_pong_timeouts: u32,
+    /// Extra headers of the HTTP upgrade request, e.g. `Authorization` or `Cookie`.
+    pub headers: HeaderMap,
+    /// Offered in `Sec-WebSocket-Protocol`; the server has to pick one of them.
+    pub subprotocols: Vec<String>,
+    /// Sent in a `Handshake` as the first message; empty sends a plain `Ping`.
+    pub credentials: Vec<u8>,
+    /// Send retries before the connection is treated as broken.
     pub retry_seconds: u64,
+    /// A ping is sent when nothing was received from the server for this long.
+    pub ping_interval_seconds: u64,
+    /// The server is treated as dead when nothing was received for this long.
+    pub pong_timeout_seconds: u64,
+    pub reconnect_delay_seconds: u64,
+    pub status_channel: ChannelOptions,
+    pub handle_message_channel: ChannelOptions,
+    pub outbound_channel: ChannelOptions,
     pub use_keep_ip: bool,
+    /// Where the internal client looks for the server when no address is saved.
+    pub scan: ScanOptions,
+    /// Listens for UDP announcements before scanning; `None` only scans.
+    pub discovery: Option<DiscoveryOptions>,
+    /// Removes the saved `ServerConnectInfo` when the server sends `Expired`.
+    pub clear_connect_info_on_expired: bool,
     pub connect_timeout_seconds: u64,
-    #[cfg(feature = "native_tls")]
+    #[cfg(any(feature = "native_tls", feature = "rustls"))]
     pub use_tls: bool,
+    /// Trust roots and pins of every `wss://` connection, outer and internal.
+    #[cfg(any(feature = "native_tls", feature = "rustls"))]
+    pub tls: ClientTlsOptions,
 }
 
 impl Default for ClientOptions {
@@ -36,72 +88,162 @@ impl Default for ClientOptions {
         Self {
             use_ping: true,
             url: "".into(),
+            fallback_urls: Vec::new(),
+            failover_pong_timeouts: 2,
+            headers: HeaderMap::new(),
+            subprotocols: Vec::new(),
+            credentials: Vec::new(),
             retry_seconds: 30,
+            ping_interval_seconds: 30,
+            pong_timeout_seconds: 90,
+            reconnect_delay_seconds: 0,
+            status_channel: ChannelOptions::new(8, BackpressurePolicy::DropNewest),
+            handle_message_channel: ChannelOptions::new(8, BackpressurePolicy::DropNewest),
+            outbound_channel: ChannelOptions::new(8, BackpressurePolicy::Block),
             use_keep_ip: false,
+            scan: ScanOptions::default(),
+            discovery: None,
+            clear_connect_info_on_expired: false,
             connect_timeout_seconds: 3,
-            #[cfg(feature = "native_tls")]
+            #[cfg(any(feature = "native_tls", feature = "rustls"))]
             use_tls: true,
+            #[cfg(any(feature = "native_tls", feature = "rustls"))]
+            tls: ClientTlsOptions::default(),
         }
     }
 }
 
+#[must_use = "dropping the client stops its background tasks and scans"]
 pub struct AtomicClient {
     pub server_sender: Arc<RwLock<ServerSender>>,
     pub options: ClientOptions,
+    is_closed: Arc<AtomicBool>,
+    /// The `ServerSender::generation` this client owns.
+    generation: u64,
+    loop_handle: Mutex<Option<JoinHandle<()>>>,
 }
 
 impl AtomicClient {
-    pub async fn internal_initialize(&self, db: Arc<RwLock<Database<'static>>>) {
-        self.regist_id(db.clone()).await;
-        tokio::spawn(internal_ping_loop_cheker(
+    pub(crate) async fn new(
+        server_sender: Arc<RwLock<ServerSender>>,
+        options: ClientOptions,
+    ) -> Self {
+        let clone = server_sender.read().await;
+        let is_closed = clone.is_closed.clone();
+        let generation = clone.generation;
+        drop(clone);
+        Self {
+            server_sender,
+            options,
+            is_closed,
+            generation,
+            loop_handle: Mutex::new(None),
+        }
+    }
+
+    pub async fn internal_initialize(&self, db: Arc<RwLock<Database<'static>>>) -> Result<()> {
+        self.regist_id(db.clone()).await?;
+        self.server_sender.send_status(SenderStatus::Start).await;
+        self.set_loop_handle(tokio::spawn(internal_ping_loop_cheker(
             self.server_sender.clone(),
             self.options.clone(),
-        ));
+        )));
+        Ok(())
     }
 
-    pub async fn outer_initialize(&self, db: Arc<RwLock<Database<'static>>>) {
-        self.regist_id(db.clone()).await;
-        tokio::spawn(outer_ping_loop_cheker(
+    pub async fn outer_initialize(&self, db: Arc<RwLock<Database<'static>>>) -> Result<()> {
+        self.regist_id(db.clone()).await?;
+        if let Some(setting) =
+            get_setting_by_key(db.clone(), format!("{:?}", SaveKey::OuterUrl)).await?
+        {
+            let url = String::from_utf8(setting.value)?;
+            self.server_sender.write().await.endpoints.select(&url);
+        }
+        self.server_sender.send_status(SenderStatus::Start).await;
+        self.set_loop_handle(tokio::spawn(outer_ping_loop_cheker(
             self.server_sender.clone(),
             self.options.clone(),
-        ));
+        )));
+        Ok(())
     }
 
-    pub async fn get_outer_connect(
-        &self,
-        db: Arc<RwLock<Database<'static>>>,
-    ) -> Result<(), Box<dyn Error>> {
+    /// Sends `Disconnect` to the server and stops the ping/reconnect loop, any
+    /// running scan and pending connects. The client can not be connected again afterwards.
+    pub async fn disconnect(&self) {
+        self.is_closed.set_bool(true);
+        self.abort_loop();
+        if !close_if_owner(&self.server_sender, self.generation).await {
+            return;
+        }
+        self.server_sender
+            .send_status(SenderStatus::Disconnected {
+                reason: DisconnectReason::ClientClosed,
+            })
+            .await;
+    }
+
+    /// Stops a running scan. Unless the client is disconnected, the ping loop
+    /// starts a new one on its next check.
+    pub async fn cancel_scan(&self) {
+        self.server_sender.write().await.cancel_scan();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.is_closed.is_true()
+    }
+
+    pub async fn is_expired(&self) -> bool {
+        self.server_sender.read().await.is_expired
+    }
+
+    fn set_loop_handle(&self, handle: JoinHandle<()>) {
+        let mut loop_handle = self.loop_handle.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(before) = loop_handle.replace(handle) {
+            before.abort();
+        }
+    }
+
+    fn abort_loop(&self) {
+        let mut loop_handle = self.loop_handle.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(handle) = loop_handle.take() {
+            handle.abort();
+        }
+    }
+
+    /// Also resumes a client expired by the server.
+    pub async fn get_outer_connect(&self, db: Arc<RwLock<Database<'static>>>) -> Result<()> {
+        self.server_sender.write().await.is_expired = false;
         get_outer_connect(db, self.server_sender.clone(), self.options.clone()).await
     }
 
+    /// Also resumes a client expired by the server.
     pub async fn get_internal_connect(
         &self,
         input: Option<ServerConnectInfo<'_>>,
         db: Arc<RwLock<Database<'static>>>,
-    ) -> Result<(), Box<dyn Error>> {
+    ) -> Result<()> {
+        self.server_sender.write().await.is_expired = false;
         get_internal_connect(input, db, self.server_sender.clone(), self.options.clone()).await
     }
 
-    pub async fn regist_id(&self, db: Arc<RwLock<Database<'static>>>) {
+    pub async fn regist_id(&self, db: Arc<RwLock<Database<'static>>>) -> Result<()> {
         let db = db.read().await;
-        let reader = db.r_transaction().unwrap();
+        let reader = db.r_transaction()?;
         let data = reader
             .get()
-            .primary::<Settings>(format!("{:?}", SaveKey::ClientId))
-            .unwrap();
+            .primary::<Settings>(format!("{:?}", SaveKey::ClientId))?;
         drop(reader);
-        if let None = data {
+        if data.is_none() {
             use nanoid::nanoid;
-            let writer = db.rw_transaction().unwrap();
-            writer
-                .insert::<Settings>(Settings {
-                    key: format!("{:?}", SaveKey::ClientId),
-                    value: nanoid!().as_bytes().to_vec(),
-                })
-                .unwrap();
-            writer.commit().unwrap();
+            let writer = db.rw_transaction()?;
+            writer.insert::<Settings>(Settings {
+                key: format!("{:?}", SaveKey::ClientId),
+                value: nanoid!().as_bytes().to_vec(),
+            })?;
+            writer.commit()?;
         }
         drop(db);
+        Ok(())
     }
 
     pub async fn get_status_receiver(&self) -> Receiver<SenderStatus> {
@@ -111,139 +253,316 @@ impl AtomicClient {
     pub async fn get_handle_message_receiver(&self) -> Receiver<Vec<u8>> {
         self.server_sender.get_handle_message_receiver().await
     }
+
+    pub async fn dropped_messages(&self) -> DroppedMessages {
+        self.server_sender.read().await.dropped_messages()
+    }
+
+    /// Health of the outer endpoints in `url`, `fallback_urls` order.
+    pub async fn endpoints(&self) -> Vec<EndpointHealth> {
+        self.server_sender.read().await.endpoints.health()
+    }
+
+    /// The upgrade response of the current outer connection, `None` while disconnected.
+    pub async fn upgrade_response(&self) -> Option<UpgradeResponse> {
+        self.server_sender.read().await.upgrade_response.clone()
+    }
+
+    /// Takes the handle message receiver and dispatches it through `router`.
+    pub async fn route(&self, router: Router) -> JoinHandle<()> {
+        tokio::spawn(router.run(self.get_handle_message_receiver().await))
+    }
+
+    pub async fn request(
+        &self,
+        category: u16,
+        datas: Vec<u8>,
+        timeout: Duration,
+    ) -> Result<Vec<u8>> {
+        self.server_sender.request(category, datas, timeout).await
+    }
+}
+
+impl Drop for AtomicClient {
+    fn drop(&mut self) {
+        self.is_closed.set_bool(true);
+        self.abort_loop();
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            let server_sender = self.server_sender.clone();
+            let generation = self.generation;
+            handle.spawn(async move { close_if_owner(&server_sender, generation).await });
+        }
+    }
+}
+
+/// Closes `server_sender` unless a later client took it over.
+async fn close_if_owner(server_sender: &Arc<RwLock<ServerSender>>, generation: u64) -> bool {
+    let mut clone = server_sender.write().await;
+    let is_owner = clone.generation == generation;
+    if is_owner {
+        clone.close();
+    }
+    is_owner
+}
+
+enum Heartbeat {
+    Alive,
+    Ping,
+    Dead,
+}
+
+impl ClientOptions {
+    pub(crate) fn outer_urls(&self) -> Vec<String> {
+        std::iter::once(&self.url)
+            .chain(&self.fallback_urls)
+            .filter(|url| !url.is_empty())
+            .cloned()
+            .collect()
+    }
+
+    pub(crate) fn outer_url(&self, url: &str) -> String {
+        if url.contains("://") {
+            return url.into();
+        }
+        #[cfg(any(feature = "native_tls", feature = "rustls"))]
+        if self.use_tls {
+            return format!("wss://{}", url);
+        }
+        format!("ws://{}", url)
+    }
+
+    pub(crate) fn outer_request(&self, url: &str) -> Result<Request> {
+        let mut request = self.outer_url(url).into_client_request()?;
+        let headers = request.headers_mut();
+        headers.extend(self.headers.clone());
+        if !self.subprotocols.is_empty() {
+            let subprotocols = HeaderValue::from_str(&self.subprotocols.join(", "))
+                .map_err(|error| AtomicWebsocketError::Handshake(error.to_string()))?;
+            headers.insert(SEC_WEBSOCKET_PROTOCOL, subprotocols);
+        }
+        Ok(request)
+    }
+
+    /// `wss://` goes through `ClientTlsOptions` so pins are checked before the upgrade.
+    pub(crate) async fn connect(
+        &self,
+        request: Request,
+    ) -> Result<(WebSocketStream<MaybeTlsStream<TcpStream>>, Response)> {
+        #[cfg(any(feature = "native_tls", feature = "rustls"))]
+        if request.uri().scheme_str() == Some("wss") {
+            let stream = self.tls.connect(&request).await?;
+            return Ok(client_async_with_config(request, stream, None).await?);
+        }
+        Ok(connect_async(request).await?)
+    }
+
+    fn heartbeat(&self, server_sender: &ServerSender) -> Heartbeat {
+        let now = now().timestamp();
+        // A server that accepted the socket but never answered still times out.
+        let received_times = server_sender
+            .server_received_times
+            .max(server_sender.connected_times);
+        if server_sender.server_ip.is_empty()
+            || received_times > 0 && received_times + (self.pong_timeout_seconds as i64) < now
+        {
+            Heartbeat::Dead
+        } else if received_times + (self.ping_interval_seconds as i64) < now {
+            log_debug!("send: {:?}, current: {:?}", received_times, now);
+            Heartbeat::Ping
+        } else {
+            Heartbeat::Alive
+        }
+    }
+
+    /// A pong timeout shorter than the ping interval is still noticed in time.
+    fn check_interval(&self) -> Duration {
+        let seconds = self.ping_interval_seconds.min(self.pong_timeout_seconds);
+        Duration::from_secs(seconds.max(1))
+    }
+}
+
+/// Reports the lost connection and the upcoming attempt, returns the delay before it.
+async fn report_reconnect(
+    server_sender: &Arc<RwLock<ServerSender>>,
+    options: &ClientOptions,
+) -> Duration {
+    let mut clone = server_sender.write().await;
+    let is_connected = !clone.server_ip.is_empty();
+    let attempt = clone.next_reconnect_attempt();
+    drop(clone);
+    if is_connected {
+        server_sender
+            .send_status(SenderStatus::Disconnected {
+                reason: DisconnectReason::Timeout,
+            })
+            .await;
+    }
+    let delay = Duration::from_secs(options.reconnect_delay_seconds);
+    server_sender
+        .send_status(SenderStatus::Reconnecting { attempt, delay })
+        .await;
+    delay
 }
 
 async fn internal_ping_loop_cheker(
     server_sender: Arc<RwLock<ServerSender>>,
     options: ClientOptions,
 ) {
-    let retry_seconds = options.retry_seconds;
     let use_keep_ip = options.use_keep_ip;
-    let mut interval = tokio::time::interval_at(
-        Instant::now() + Duration::from_secs(retry_seconds),
-        Duration::from_secs(retry_seconds),
-    );
+    let check_interval = options.check_interval();
+    let mut interval = tokio::time::interval_at(Instant::now() + check_interval, check_interval);
     interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
 
     loop {
         interval.tick().await;
-        let server_sender_read = server_sender.read().await;
-        if server_sender_read.server_received_times > 0
-            && server_sender_read.server_received_times + (retry_seconds as i64 * 3)
-                < now().timestamp()
-            || server_sender_read.server_ip.is_empty()
-        {
-            drop(server_sender_read);
-            server_sender.send_status(SenderStatus::Disconnected).await;
-            if !use_keep_ip {
-                server_sender.remove_ip().await;
-                let db = server_sender.read().await.db.clone();
-                let server_connect_info = match get_setting_by_key(
-                    db.clone(),
-                    format!("{:?}", SaveKey::ServerConnectInfo),
-                )
-                .await
-                {
-                    Ok(server_connect_info) => server_connect_info,
-                    Err(error) => {
-                        log_error!("Failed to get server_connect_info {error:?}");
-                        None
+        let clone = server_sender.read().await;
+        // An expired client waits for an explicit connect.
+        if clone.is_expired {
+            continue;
+        }
+        let heartbeat = options.heartbeat(&clone);
+        drop(clone);
+        match heartbeat {
+            // A running scan already is the reconnect attempt.
+            Heartbeat::Dead if server_sender.read().await.is_try_connect => {}
+            Heartbeat::Dead => {
+                let delay = report_reconnect(&server_sender, &options).await;
+                if !use_keep_ip {
+                    server_sender.remove_ip().await;
+                    let db = server_sender.read().await.db.clone();
+                    if let Err(error) = clear_server_connect_ip(db).await {
+                        log_error!("Failed to clear server_connect_info {error:?}");
+                        server_sender.send_status(SenderStatus::Error(error)).await;
                     }
-                };
-                if let Some(server_connect_info) = server_connect_info {
-                    let mut info =
-                        ServerConnectInfo::deserialize(&server_connect_info.value).unwrap();
-                    info.server_ip = "".into();
-                    let mut value = Vec::new();
-                    info.serialize(&mut value).unwrap();
-                    let db = db.read().await;
-                    let writer = db.rw_transaction().unwrap();
-                    writer
-                        .update::<Settings>(
-                            server_connect_info,
-                            Settings {
-                                key: format!("{:?}", SaveKey::ServerConnectInfo),
-                                value,
-                            },
-                        )
-                        .unwrap();
-                    writer.commit().unwrap();
                 }
-                drop(db);
+                let mut clone = server_sender.write().await;
+                let db = clone.db.clone();
+                let server_sender = server_sender.clone();
+                let options = options.clone();
+                clone.spawn(async move {
+                    sleep(delay).await;
+                    if let Err(error) =
+                        get_internal_connect(None, db, server_sender.clone(), options).await
+                    {
+                        log_error!("Failed to connect {error:?}");
+                        server_sender.send_status(SenderStatus::Error(error)).await;
+                    }
+                });
             }
-            let db = server_sender.read().await.db.clone();
-            let server_sender = server_sender.clone();
-            let options = options.clone();
-            tokio::spawn(async move {
-                let _ = get_internal_connect(None, db, server_sender, options).await;
-                true
-            });
-        } else if server_sender_read.server_received_times + (retry_seconds as i64 * 2)
-            < now().timestamp()
-        {
-            log_debug!(
-                "send: {:?}, current: {:?}",
-                server_sender_read.server_received_times,
-                now().timestamp()
-            );
-            log_debug!("Try ping from loop checker");
-            let id: String = get_id(server_sender_read.db.clone()).await;
-            drop(server_sender_read);
-            server_sender.send(make_ping_message(&id)).await;
+            Heartbeat::Ping => {
+                log_debug!("Try ping from loop checker");
+                let db = server_sender.read().await.db.clone();
+                send_ping(&server_sender, db).await;
+            }
+            Heartbeat::Alive => {}
         }
         log_debug!("loop server checker finish");
     }
 }
 
 async fn outer_ping_loop_cheker(server_sender: Arc<RwLock<ServerSender>>, options: ClientOptions) {
-    let retry_seconds = options.retry_seconds;
     let use_keep_ip = options.use_keep_ip;
-    let mut interval = tokio::time::interval_at(
-        Instant::now() + Duration::from_secs(retry_seconds),
-        Duration::from_secs(retry_seconds),
-    );
+    let check_interval = options.check_interval();
+    let mut interval = tokio::time::interval_at(Instant::now() + check_interval, check_interval);
     interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
 
     loop {
         interval.tick().await;
-        let server_sender_read = server_sender.read().await;
-        if server_sender_read.server_received_times + 90 < now().timestamp()
-            || server_sender_read.server_ip.is_empty()
-        {
-            drop(server_sender_read);
-            server_sender.send_status(SenderStatus::Disconnected).await;
-
-            if !use_keep_ip {
-                server_sender.remove_ip().await;
+        let clone = server_sender.read().await;
+        // An expired client waits for an explicit connect.
+        if clone.is_expired {
+            continue;
+        }
+        let heartbeat = options.heartbeat(&clone);
+        drop(clone);
+        match heartbeat {
+            Heartbeat::Dead => {
+                // Only a session that stopped answering counts as a pong timeout.
+                let is_connected = !server_sender.read().await.server_ip.is_empty();
+                let delay = report_reconnect(&server_sender, &options).await;
+                if is_connected {
+                    let failover = server_sender
+                        .write()
+                        .await
+                        .endpoints
+                        .pong_timeout(options.failover_pong_timeouts);
+                    if let Some((from, to)) = failover {
+                        server_sender
+                            .send_status(SenderStatus::Failover { from, to })
+                            .await;
+                    }
+                }
+                if !use_keep_ip {
+                    server_sender.remove_ip().await;
+                }
+                let mut clone = server_sender.write().await;
+                let db = clone.db.clone();
+                let server_sender = server_sender.clone();
+                let options = options.clone();
+                clone.spawn(async move {
+                    sleep(delay).await;
+                    if let Err(error) = get_outer_connect(db, server_sender.clone(), options).await
+                    {
+                        log_error!("Failed to connect {error:?}");
+                        server_sender.send_status(SenderStatus::Error(error)).await;
+                    }
+                });
             }
-
-            let server_sender = server_sender.clone();
-            let options = options.clone();
-            let db = server_sender.read().await.db.clone();
-            tokio::spawn(async move {
-                let _ = get_outer_connect(db, server_sender, options).await;
-                true
-            });
-        } else if server_sender_read.server_received_times + 30 < now().timestamp() {
-            log_debug!(
-                "send: {:?}, current: {:?}",
-                server_sender_read.server_received_times,
-                now().timestamp()
-            );
-            log_debug!("Try ping from loop checker");
-            let id: String = get_id(server_sender_read.db.clone()).await;
-            drop(server_sender_read);
-            server_sender.send(make_ping_message(&id)).await;
+            Heartbeat::Ping => {
+                log_debug!("Try ping from loop checker");
+                let db = server_sender.read().await.db.clone();
+                send_ping(&server_sender, db).await;
+            }
+            Heartbeat::Alive => {}
         }
         log_debug!("loop server checker finish");
     }
 }
 
+async fn send_ping(server_sender: &Arc<RwLock<ServerSender>>, db: Arc<RwLock<Database<'static>>>) {
+    let result = match get_id(db).await {
+        Ok(id) => server_sender.send(make_ping_message(&id)).await,
+        Err(error) => Err(error),
+    };
+    if let Err(error) = result {
+        log_error!("Failed to send ping {error:?}");
+        server_sender.send_status(SenderStatus::Error(error)).await;
+    }
+}
+
+async fn clear_server_connect_ip(db: Arc<RwLock<Database<'static>>>) -> Result<()> {
+    let server_connect_info =
+        get_setting_by_key(db.clone(), format!("{:?}", SaveKey::ServerConnectInfo)).await?;
+    if let Some(server_connect_info) = server_connect_info {
+        let mut info = ServerConnectInfo::deserialize(&server_connect_info.value)?;
+        info.server_ip = "";
+        let mut value = Vec::new();
+        info.serialize(&mut value)?;
+        let db = db.read().await;
+        let writer = db.rw_transaction()?;
+        writer.update::<Settings>(
+            server_connect_info,
+            Settings {
+                key: format!("{:?}", SaveKey::ServerConnectInfo),
+                value,
+            },
+        )?;
+        writer.commit()?;
+    }
+    Ok(())
+}
+
 pub async fn get_outer_connect(
     db: Arc<RwLock<Database<'static>>>,
     server_sender: Arc<RwLock<ServerSender>>,
     options: ClientOptions,
-) -> Result<(), Box<dyn Error>> {
+) -> Result<()> {
+    if server_sender.read().await.is_closed.is_true() {
+        return Err(AtomicWebsocketError::Closed);
+    }
+    if server_sender.read().await.is_expired {
+        return Err(AtomicWebsocketError::Expired);
+    }
     if server_sender.is_valid_server_ip().await {
         server_sender.send_status(SenderStatus::Connected).await;
         return Ok(());
@@ -252,21 +571,31 @@ pub async fn get_outer_connect(
         get_setting_by_key(db.clone(), format!("{:?}", SaveKey::ServerConnectInfo)).await?;
     log_debug!("server_connect_info: {:?}", server_connect_info);
 
-    if options.url.is_empty() && !server_sender.is_valid_server_ip().await {
-        server_sender.send_status(SenderStatus::Disconnected).await;
+    if options.outer_urls().is_empty() {
+        server_sender.send_status(not_connected()).await;
         return Ok(());
     }
 
-    tokio::spawn(wrap_get_outer_websocket(db, server_sender, options));
+    let connect = wrap_get_outer_websocket(db, server_sender.clone(), options);
+    server_sender.write().await.spawn(connect);
     Ok(())
 }
 
+/// Connects to the saved server, or else to `input.server_ip`, either a full
+/// `ws://`/`wss://` URL or `host:port` with the scheme of `ScanOptions::use_tls`.
+/// Without either it discovers and scans `input.port`.
 pub async fn get_internal_connect(
     input: Option<ServerConnectInfo<'_>>,
     db: Arc<RwLock<Database<'static>>>,
     server_sender: Arc<RwLock<ServerSender>>,
     options: ClientOptions,
-) -> Result<(), Box<dyn Error>> {
+) -> Result<()> {
+    if server_sender.read().await.is_closed.is_true() {
+        return Err(AtomicWebsocketError::Closed);
+    }
+    if server_sender.read().await.is_expired {
+        return Err(AtomicWebsocketError::Expired);
+    }
     if server_sender.read().await.is_try_connect {
         return Ok(());
     }
@@ -278,14 +607,17 @@ pub async fn get_internal_connect(
         get_setting_by_key(db.clone(), format!("{:?}", SaveKey::ServerConnectInfo)).await?;
     log_debug!("server_connect_info: {:?}", server_connect_info);
 
-    if input.is_some() && server_connect_info.is_none() {
-        let input = input.as_ref().unwrap();
+    let input_server_ip = input
+        .as_ref()
+        .map(|input| options.scan.url(input.server_ip))
+        .unwrap_or_default();
+    if let (Some(input), None) = (input.as_ref(), server_connect_info.as_ref()) {
         let db_clone = db.read().await;
         let writer = db_clone.rw_transaction()?;
         let mut value = Vec::new();
         ServerConnectInfo {
-            server_ip: "".into(),
-            port: &input.port,
+            server_ip: &input_server_ip,
+            port: input.port,
         }
         .serialize(&mut value)?;
         writer.insert::<Settings>(Settings {
@@ -296,42 +628,67 @@ pub async fn get_internal_connect(
         drop(db_clone);
     }
 
-    if input.is_none() && server_connect_info.is_none() {
-        server_sender.send_status(SenderStatus::Disconnected).await;
-        return Ok(());
-    }
-
-    let connect_info_data = match input.as_ref() {
-        Some(info) => ServerConnectInfo {
-            server_ip: match server_connect_info.as_ref() {
-                Some(server_connect_info) => {
-                    ServerConnectInfo::deserialize(&server_connect_info.value)
-                        .unwrap()
-                        .server_ip
-                }
-                None => "".into(),
-            },
-            port: &info.port,
+    let connect_info_data = match (input.as_ref(), server_connect_info.as_ref()) {
+        (Some(info), Some(server_connect_info)) => ServerConnectInfo {
+            server_ip: ServerConnectInfo::deserialize(&server_connect_info.value)?.server_ip,
+            port: info.port,
         },
-        None => {
-            ServerConnectInfo::deserialize(&server_connect_info.as_ref().unwrap().value).unwrap()
+        (Some(info), None) => ServerConnectInfo {
+            server_ip: &input_server_ip,
+            port: info.port,
+        },
+        (None, Some(server_connect_info)) => {
+            ServerConnectInfo::deserialize(&server_connect_info.value)?
+        }
+        (None, None) => {
+            server_sender.send_status(not_connected()).await;
+            return Ok(());
         }
     };
 
-    let ip = get_ip_address();
-
-    if ip.is_empty() {
-        server_sender.send_status(SenderStatus::Disconnected).await;
+    if options.scan.local_ip_address().is_none() {
+        server_sender.send_status(not_connected()).await;
         return Ok(());
     }
 
     match connect_info_data.server_ip {
         "" => {
-            server_sender.write().await.is_try_connect = true;
+            let cancel = ScanCancel::new();
+            server_sender.write().await.start_scan(cancel.clone());
+            if let Some(discovery) = options.discovery.clone() {
+                let discovered = discover_server(&server_sender, &discovery, &cancel).await;
+                if let Some(announcement) = discovered {
+                    server_sender.write().await.end_scan();
+                    server_sender
+                        .send_status(SenderStatus::Discovered(announcement.clone()))
+                        .await;
+                    spawn_internal_websocket(db, server_sender, announcement.url(), options).await;
+                    return Ok(());
+                }
+                if cancel.is_cancelled() {
+                    server_sender.write().await.end_scan();
+                    log_debug!("Discovery cancelled");
+                    return Ok(());
+                }
+            }
+            server_sender.send_status(SenderStatus::Scanning).await;
 
-            let (server_ip, ws_stream) = ScanManager::new(connect_info_data.port).run().await;
-            server_sender.write().await.is_try_connect = false;
-            tokio::spawn(async move {
+            let scanned = ScanManager::new(connect_info_data.port, &options)
+                .run(cancel)
+                .await;
+            server_sender.write().await.end_scan();
+            let Some((server_ip, ws_stream)) = scanned? else {
+                log_debug!("Scan cancelled");
+                return Ok(());
+            };
+            // The socket is open, the handshake message is sent next.
+            server_sender
+                .send_status(SenderStatus::Connecting {
+                    target: server_ip.clone(),
+                })
+                .await;
+            let connected = server_sender.clone();
+            connected.write().await.spawn(async move {
                 if let Err(error) =
                     handle_websocket(db, server_sender, options, server_ip, ws_stream).await
                 {
@@ -340,31 +697,954 @@ pub async fn get_internal_connect(
             });
         }
         _server_ip => {
-            tokio::spawn(wrap_get_internal_websocket(
-                db.clone(),
-                server_sender.clone(),
-                _server_ip.into(),
-                options.clone(),
-            ));
+            spawn_internal_websocket(db, server_sender, _server_ip.into(), options).await;
         }
     };
 
     Ok(())
 }
 
+/// The first accepted announcement, `None` when discovery timed out or was cancelled.
+async fn discover_server(
+    server_sender: &Arc<RwLock<ServerSender>>,
+    options: &DiscoveryOptions,
+    cancel: &ScanCancel,
+) -> Option<Announcement> {
+    server_sender.send_status(SenderStatus::Discovering).await;
+    tokio::select! {
+        discovered = discover(options) => match discovered {
+            Ok(announcement) => Some(announcement),
+            Err(error) => {
+                log_debug!("No server announced, falling back to scan: {:?}", error);
+                None
+            }
+        },
+        _ = cancel.cancelled() => None,
+    }
+}
+
+fn not_connected() -> SenderStatus {
+    SenderStatus::Disconnected {
+        reason: DisconnectReason::Error(AtomicWebsocketError::NotConnected),
+    }
+}
+
+/// The local IPv4 address found on the interfaces, empty when there is none.
 pub fn get_ip_address() -> String {
-    let socket = UdpSocket::bind("0.0.0.0:0");
-    let socket = match socket {
-        Ok(socket) => socket,
-        Err(_) => return "".into(),
+    local_ip_address(None, false)
+        .map(|ip| ip.to_string())
+        .unwrap_or_default()
+}
+
+#[tokio::test]
+async fn test_disconnect_stops_client() {
+    use crate::{
+        helpers::internal_server::{AtomicServer, ServerOptions},
+        test_db, AtomicWebsocket,
+    };
+
+    let server = AtomicServer::new("127.0.0.1:0", ServerOptions::default(), None)
+        .await
+        .unwrap();
+    let db = test_db();
+    let options = ClientOptions {
+        url: format!("ws://{}", server.local_addr()),
+        ..Default::default()
+    };
+    let client = AtomicWebsocket::get_outer_client(db.clone(), options)
+        .await
+        .unwrap();
+    let mut status_receiver = client.get_status_receiver().await;
+    client.get_outer_connect(db.clone()).await.unwrap();
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while status_receiver.recv().await != Some(SenderStatus::Connected) {}
+    })
+    .await
+    .unwrap();
+
+    client.disconnect().await;
+    assert_eq!(
+        status_receiver.recv().await,
+        Some(SenderStatus::Disconnected {
+            reason: DisconnectReason::ClientClosed
+        })
+    );
+    assert!(client.is_closed());
+    assert!(client.server_sender.read().await.server_ip.is_empty());
+    assert_eq!(
+        client.get_outer_connect(db).await,
+        Err(AtomicWebsocketError::Closed)
+    );
+}
+
+#[tokio::test]
+async fn test_disconnect_while_connecting() {
+    use crate::{test_db, AtomicWebsocket};
+    use futures_util::StreamExt;
+    use tokio::net::TcpListener;
+
+    // Accepts the socket but answers the upgrade only after the disconnect.
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server_ip = format!("ws://{}", listener.local_addr().unwrap());
+    let server = tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        sleep(Duration::from_millis(300)).await;
+        match tokio_tungstenite::accept_async(stream).await {
+            Ok(mut ws_stream) => ws_stream
+                .next()
+                .await
+                .is_some_and(|message| message.is_ok()),
+            Err(_) => false,
+        }
+    });
+    let db = test_db();
+    let client = AtomicWebsocket::get_internal_client(db.clone(), ClientOptions::default())
+        .await
+        .unwrap();
+    let mut status_receiver = client.get_status_receiver().await;
+    client
+        .get_internal_connect(
+            Some(ServerConnectInfo {
+                server_ip: &server_ip,
+                port: "",
+            }),
+            db,
+        )
+        .await
+        .unwrap();
+    sleep(Duration::from_millis(100)).await;
+    client.disconnect().await;
+
+    let is_received = tokio::time::timeout(Duration::from_secs(2), server)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(!is_received);
+    assert!(client.server_sender.read().await.server_ip.is_empty());
+    let mut statuses = Vec::new();
+    while let Ok(status) = status_receiver.try_recv() {
+        statuses.push(status);
+    }
+    assert_eq!(
+        statuses.last(),
+        Some(&SenderStatus::Disconnected {
+            reason: DisconnectReason::ClientClosed
+        })
+    );
+    assert!(!statuses.contains(&SenderStatus::Connected));
+}
+
+#[tokio::test]
+async fn test_drop_earlier_client_of_shared_sender() {
+    use crate::{
+        helpers::internal_server::{AtomicServer, ServerOptions},
+        test_db, AtomicWebsocket,
+    };
+
+    let server = AtomicServer::new("127.0.0.1:0", ServerOptions::default(), None)
+        .await
+        .unwrap();
+    let db = test_db();
+    let options = ClientOptions {
+        url: format!("ws://{}", server.local_addr()),
+        ..Default::default()
+    };
+    let first = AtomicWebsocket::get_outer_client(db.clone(), options.clone())
+        .await
+        .unwrap();
+    let second = AtomicWebsocket::get_outer_client_with_server_sender(
+        db.clone(),
+        options,
+        first.server_sender.clone(),
+    )
+    .await
+    .unwrap();
+    let mut status_receiver = second.get_status_receiver().await;
+    second.get_outer_connect(db).await.unwrap();
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while status_receiver.recv().await != Some(SenderStatus::Connected) {}
+    })
+    .await
+    .unwrap();
+
+    drop(first);
+    sleep(Duration::from_millis(200)).await;
+    assert!(!second.is_closed());
+    assert!(!second.server_sender.read().await.server_ip.is_empty());
+    assert!(status_receiver.try_recv().is_err());
+    second
+        .server_sender
+        .send(make_ping_message("peer"))
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_internal_connect_bare_address() {
+    use crate::{
+        helpers::internal_server::{AtomicServer, ServerOptions},
+        test_db, AtomicWebsocket,
+    };
+
+    let server = AtomicServer::new("127.0.0.1:0", ServerOptions::default(), None)
+        .await
+        .unwrap();
+    let server_ip = server.local_addr().to_string();
+    let port = server.local_addr().port().to_string();
+    let db = test_db();
+    let client = AtomicWebsocket::get_internal_client(db.clone(), ClientOptions::default())
+        .await
+        .unwrap();
+    let mut status_receiver = client.get_status_receiver().await;
+    client
+        .get_internal_connect(
+            Some(ServerConnectInfo {
+                server_ip: &server_ip,
+                port: &port,
+            }),
+            db.clone(),
+        )
+        .await
+        .unwrap();
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while status_receiver.recv().await != Some(SenderStatus::Connected) {}
+    })
+    .await
+    .unwrap();
+
+    let target = format!("ws://{}", server_ip);
+    assert_eq!(client.server_sender.read().await.server_ip, target);
+    let saved = get_setting_by_key(db, format!("{:?}", SaveKey::ServerConnectInfo))
+        .await
+        .unwrap()
+        .unwrap();
+    let saved = ServerConnectInfo::deserialize(&saved.value).unwrap();
+    assert_eq!(
+        (saved.server_ip, saved.port),
+        (target.as_str(), port.as_str())
+    );
+}
+
+#[tokio::test]
+async fn test_request_response() {
+    use crate::{
+        helpers::{
+            client_sender::ClientSendersTrait,
+            common::{get_rpc_request, make_rpc_error_message},
+            internal_server::{AtomicServer, ServerOptions},
+        },
+        schema::{Data, RpcRequest},
+        test_db, AtomicWebsocket,
+    };
+
+    let server = AtomicServer::new("127.0.0.1:0", ServerOptions::default(), None)
+        .await
+        .unwrap();
+    let mut server_receiver = server.get_handle_message_receiver().await;
+    let db = test_db();
+    let options = ClientOptions {
+        url: format!("ws://{}", server.local_addr()),
+        ..Default::default()
+    };
+    let client = AtomicWebsocket::get_outer_client(db.clone(), options)
+        .await
+        .unwrap();
+    let mut status_receiver = client.get_status_receiver().await;
+    let mut client_receiver = client.get_handle_message_receiver().await;
+    client.get_outer_connect(db).await.unwrap();
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while status_receiver.recv().await != Some(SenderStatus::Connected) {}
+    })
+    .await
+    .unwrap();
+
+    // client -> server
+    let client_sender = client.server_sender.clone();
+    let request = tokio::spawn(async move {
+        client_sender
+            .request(100, b"hello".to_vec(), Duration::from_secs(5))
+            .await
+    });
+    let (message, peer) = server_receiver.recv().await.unwrap();
+    let (id, category, datas) = get_rpc_request(&message).unwrap();
+    assert_eq!((category, datas), (100, b"hello".to_vec()));
+    server
+        .client_senders
+        .respond(&peer, id, b"world".to_vec())
+        .await
+        .unwrap();
+    assert_eq!(request.await.unwrap(), Ok(b"world".to_vec()));
+
+    // server -> client, answered with an error
+    let client_senders = server.client_senders.clone();
+    let request_peer = peer.clone();
+    let request = tokio::spawn(async move {
+        client_senders
+            .request(&request_peer, 200, Vec::new(), Duration::from_secs(5))
+            .await
+    });
+    let message = client_receiver.recv().await.unwrap();
+    let data = Data::deserialize(&message).unwrap();
+    let rpc = RpcRequest::deserialize(&data.datas).unwrap();
+    assert_eq!(rpc.category, 200);
+    client
+        .server_sender
+        .send(make_rpc_error_message(rpc.id, "denied"))
+        .await
+        .unwrap();
+    assert_eq!(
+        request.await.unwrap(),
+        Err(AtomicWebsocketError::Remote("denied".into()))
+    );
+
+    // unanswered requests time out
+    assert_eq!(
+        client
+            .request(300, Vec::new(), Duration::from_millis(100))
+            .await,
+        Err(AtomicWebsocketError::Timeout)
+    );
+}
+
+#[test]
+fn test_heartbeat_thresholds() {
+    let options = ClientOptions {
+        ping_interval_seconds: 20,
+        pong_timeout_seconds: 50,
+        ..Default::default()
+    };
+    let mut server_sender =
+        ServerSender::new(crate::test_db(), "ws://server".into(), options.clone());
+    let now = now().timestamp();
+    let heartbeat_after = |server_sender: &mut ServerSender, seconds: i64| {
+        server_sender.server_received_times = now - seconds;
+        options.heartbeat(server_sender)
+    };
+    assert!(matches!(
+        heartbeat_after(&mut server_sender, 10),
+        Heartbeat::Alive
+    ));
+    assert!(matches!(
+        heartbeat_after(&mut server_sender, 30),
+        Heartbeat::Ping
+    ));
+    assert!(matches!(
+        heartbeat_after(&mut server_sender, 60),
+        Heartbeat::Dead
+    ));
+
+    server_sender.server_received_times = 0;
+    server_sender.connected_times = now - 10;
+    assert!(matches!(
+        options.heartbeat(&server_sender),
+        Heartbeat::Alive
+    ));
+    server_sender.connected_times = now - 60;
+    assert!(matches!(options.heartbeat(&server_sender), Heartbeat::Dead));
+
+    assert_eq!(options.check_interval(), Duration::from_secs(20));
+    let options = ClientOptions {
+        ping_interval_seconds: 30,
+        pong_timeout_seconds: 5,
+        ..Default::default()
+    };
+    assert_eq!(options.check_interval(), Duration::from_secs(5));
+}
+
+#[tokio::test]
+async fn test_status_progress() {
+    use crate::{
+        helpers::internal_server::{AtomicServer, ServerOptions},
+        test_db, AtomicWebsocket,
     };
-    match socket.connect("8.8.8.8:80") {
-        Ok(_) => {}
-        Err(_) => return "".into(),
+
+    let server = AtomicServer::new("127.0.0.1:0", ServerOptions::default(), None)
+        .await
+        .unwrap();
+    let db = test_db();
+    let options = ClientOptions {
+        url: format!("ws://{}", server.local_addr()),
+        ..Default::default()
+    };
+    let client = AtomicWebsocket::get_outer_client(db.clone(), options.clone())
+        .await
+        .unwrap();
+    let mut status_receiver = client.get_status_receiver().await;
+    async fn next_status(status_receiver: &mut Receiver<SenderStatus>) -> SenderStatus {
+        tokio::time::timeout(Duration::from_secs(5), status_receiver.recv())
+            .await
+            .unwrap()
+            .unwrap()
+    }
+    assert_eq!(next_status(&mut status_receiver).await, SenderStatus::Start);
+    client.get_outer_connect(db.clone()).await.unwrap();
+    assert_eq!(
+        next_status(&mut status_receiver).await,
+        SenderStatus::Connecting {
+            target: options.url.clone()
+        }
+    );
+    assert_eq!(
+        next_status(&mut status_receiver).await,
+        SenderStatus::Connected
+    );
+
+    server.shutdown(Duration::from_secs(1)).await;
+    assert_eq!(
+        next_status(&mut status_receiver).await,
+        SenderStatus::Disconnected {
+            reason: DisconnectReason::ServerClosed
+        }
+    );
+
+    // Nothing listens on the port anymore.
+    client.get_outer_connect(db).await.unwrap();
+    assert_eq!(
+        next_status(&mut status_receiver).await,
+        SenderStatus::Connecting {
+            target: options.url.clone()
+        }
+    );
+    assert!(matches!(
+        next_status(&mut status_receiver).await,
+        SenderStatus::Error(_)
+    ));
+}
+
+#[tokio::test]
+async fn test_scan_status_progress() {
+    use crate::{
+        helpers::internal_server::{AtomicServer, ServerOptions},
+        test_db, AtomicWebsocket,
+    };
+
+    let server = AtomicServer::new("127.0.0.1:0", ServerOptions::default(), None)
+        .await
+        .unwrap();
+    let db = test_db();
+    let options = ClientOptions {
+        scan: ScanOptions {
+            ips: vec!["127.0.0.1".parse().unwrap()],
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let client = AtomicWebsocket::get_internal_client(db.clone(), options)
+        .await
+        .unwrap();
+    let mut status_receiver = client.get_status_receiver().await;
+    client
+        .get_internal_connect(
+            Some(ServerConnectInfo {
+                server_ip: "",
+                port: &server.local_addr().port().to_string(),
+            }),
+            db,
+        )
+        .await
+        .unwrap();
+
+    let mut statuses = Vec::new();
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while let Some(status) = status_receiver.recv().await {
+            let is_connected = status == SenderStatus::Connected;
+            statuses.push(status);
+            if is_connected {
+                break;
+            }
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(
+        statuses,
+        [
+            SenderStatus::Start,
+            SenderStatus::Scanning,
+            SenderStatus::Connecting {
+                target: format!("ws://{}", server.local_addr())
+            },
+            SenderStatus::Connected,
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_expired_stops_reconnect() {
+    use crate::{
+        helpers::{
+            client_sender::ClientSendersTrait,
+            internal_server::{AtomicServer, ServerOptions},
+        },
+        test_db, AtomicWebsocket,
+    };
+
+    let server = AtomicServer::new("127.0.0.1:0", ServerOptions::default(), None)
+        .await
+        .unwrap();
+    let db = test_db();
+    let options = ClientOptions {
+        url: format!("ws://{}", server.local_addr()),
+        clear_connect_info_on_expired: true,
+        ..Default::default()
     };
-    let addr = match socket.local_addr() {
-        Ok(addr) => addr,
-        Err(_) => return "".into(),
+    let client = AtomicWebsocket::get_outer_client(db.clone(), options)
+        .await
+        .unwrap();
+    let mut status_receiver = client.get_status_receiver().await;
+    async fn wait_status(status_receiver: &mut Receiver<SenderStatus>, status: SenderStatus) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while status_receiver.recv().await != Some(status.clone()) {}
+        })
+        .await
+        .unwrap()
+    }
+    client.get_outer_connect(db.clone()).await.unwrap();
+    wait_status(&mut status_receiver, SenderStatus::Connected).await;
+    let connect_info_key = format!("{:?}", SaveKey::ServerConnectInfo);
+    assert!(get_setting_by_key(db.clone(), connect_info_key.clone())
+        .await
+        .unwrap()
+        .is_some());
+
+    server.client_senders.expire_send(Vec::new()).await;
+    wait_status(&mut status_receiver, SenderStatus::Expired).await;
+    wait_status(
+        &mut status_receiver,
+        SenderStatus::Disconnected {
+            reason: DisconnectReason::ServerClosed,
+        },
+    )
+    .await;
+    assert!(client.is_expired().await);
+    assert!(get_setting_by_key(db.clone(), connect_info_key)
+        .await
+        .unwrap()
+        .is_none());
+    assert_eq!(
+        get_outer_connect(
+            db.clone(),
+            client.server_sender.clone(),
+            client.options.clone()
+        )
+        .await,
+        Err(AtomicWebsocketError::Expired)
+    );
+
+    client.get_outer_connect(db).await.unwrap();
+    wait_status(&mut status_receiver, SenderStatus::Connected).await;
+    assert!(!client.is_expired().await);
+}
+
+#[tokio::test]
+async fn test_credentials_handshake() {
+    use crate::{
+        helpers::{
+            auth::{AuthDecision, Authenticator},
+            client_sender::ClientSendersTrait,
+            internal_server::{AtomicServer, ServerOptions},
+        },
+        test_db, AtomicWebsocket,
     };
-    addr.ip().to_string()
+
+    let option = ServerOptions {
+        authenticator: Some(Authenticator::new(|request| async move {
+            if request.credentials == b"secret" {
+                AuthDecision::Accept { peer: "ok".into() }
+            } else {
+                AuthDecision::Reject {
+                    reason: "denied".into(),
+                }
+            }
+        })),
+        ..Default::default()
+    };
+    let server = AtomicServer::new("127.0.0.1:0", option, None)
+        .await
+        .unwrap();
+    for (credentials, expected) in [
+        (b"wrong".to_vec(), rejected("denied")),
+        (b"secret".to_vec(), SenderStatus::Connected),
+    ] {
+        let db = test_db();
+        let options = ClientOptions {
+            url: format!("ws://{}", server.local_addr()),
+            credentials,
+            ..Default::default()
+        };
+        let client = AtomicWebsocket::get_outer_client(db.clone(), options)
+            .await
+            .unwrap();
+        let mut status_receiver = client.get_status_receiver().await;
+        client.get_outer_connect(db).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while status_receiver.recv().await != Some(expected.clone()) {}
+        })
+        .await
+        .unwrap();
+    }
+    assert!(server.client_senders.is_active("ok").await);
+
+    fn rejected(reason: &str) -> SenderStatus {
+        SenderStatus::Disconnected {
+            reason: DisconnectReason::Error(AtomicWebsocketError::Unauthorized(reason.into())),
+        }
+    }
+}
+
+#[cfg(any(feature = "native_tls", feature = "rustls"))]
+#[tokio::test]
+async fn test_tls_client_verification() {
+    use crate::{
+        helpers::{
+            internal_server::{AtomicServer, ServerOptions},
+            tls::{CertificatePin, TlsIdentity},
+        },
+        test_db, AtomicWebsocket,
+    };
+
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+    let cert = certified.cert.pem().into_bytes();
+    let option = ServerOptions {
+        tls_identity: Some(TlsIdentity::Pem {
+            cert: cert.clone(),
+            key: certified.key_pair.serialize_pem().into_bytes(),
+        }),
+        ..Default::default()
+    };
+    let server = AtomicServer::new("127.0.0.1:0", option, None)
+        .await
+        .unwrap();
+    let url = format!("localhost:{}", server.local_addr().port());
+    let trusted = ClientTlsOptions {
+        root_certificates: vec![cert],
+        use_default_roots: false,
+        ..Default::default()
+    };
+    let public_key_pin = CertificatePin::from_certificate_public_key(certified.cert.der()).unwrap();
+
+    let cases = [
+        (
+            ClientTlsOptions {
+                use_default_roots: false,
+                ..Default::default()
+            },
+            false,
+        ),
+        (trusted.clone(), true),
+        (
+            ClientTlsOptions {
+                pins: vec![CertificatePin::Certificate([0; 32])],
+                ..trusted.clone()
+            },
+            false,
+        ),
+        (
+            ClientTlsOptions {
+                pins: vec![public_key_pin.clone()],
+                ..trusted
+            },
+            true,
+        ),
+        (
+            ClientTlsOptions {
+                accept_invalid_certs: true,
+                pins: vec![CertificatePin::from_certificate(certified.cert.der())],
+                ..Default::default()
+            },
+            true,
+        ),
+    ];
+    for (tls, is_trusted) in cases {
+        let db = test_db();
+        let options = ClientOptions {
+            url: url.clone(),
+            tls,
+            ..Default::default()
+        };
+        let client = AtomicWebsocket::get_outer_client(db.clone(), options)
+            .await
+            .unwrap();
+        let mut status_receiver = client.get_status_receiver().await;
+        client.get_outer_connect(db).await.unwrap();
+        let status = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Some(status @ (SenderStatus::Connected | SenderStatus::Error(_))) =
+                    status_receiver.recv().await
+                {
+                    break status;
+                }
+            }
+        })
+        .await
+        .unwrap();
+        match is_trusted {
+            true => assert_eq!(status, SenderStatus::Connected),
+            false => assert!(matches!(
+                status,
+                SenderStatus::Error(AtomicWebsocketError::Tls(_))
+            )),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_upgrade_headers_and_subprotocol() {
+    use crate::{helpers::common::make_pong_message, test_db, AtomicWebsocket};
+    use futures_util::{SinkExt, StreamExt};
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::tungstenite::handshake::server::{Callback, ErrorResponse, Response};
+
+    struct StandIn<'a> {
+        authorization: &'a mut Option<HeaderValue>,
+    }
+    impl Callback for StandIn<'_> {
+        fn on_request(
+            self,
+            request: &Request,
+            mut response: Response,
+        ) -> std::result::Result<Response, ErrorResponse> {
+            *self.authorization = request.headers().get("authorization").cloned();
+            let headers = response.headers_mut();
+            headers.insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static("v2"));
+            headers.insert("x-server", HeaderValue::from_static("stand-in"));
+            Ok(response)
+        }
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut authorization = None;
+        let callback = StandIn {
+            authorization: &mut authorization,
+        };
+        let mut ws_stream = tokio_tungstenite::accept_hdr_async(stream, callback)
+            .await
+            .unwrap();
+        // Answers the first ping so the client reports `Connected`.
+        ws_stream.next().await.unwrap().unwrap();
+        ws_stream.send(make_pong_message()).await.unwrap();
+        (authorization, ws_stream)
+    });
+
+    let db = test_db();
+    let mut headers = HeaderMap::new();
+    headers.insert("authorization", HeaderValue::from_static("Bearer token"));
+    let options = ClientOptions {
+        url: format!("ws://{addr}"),
+        headers,
+        subprotocols: vec!["v1".into(), "v2".into()],
+        ..Default::default()
+    };
+    let client = AtomicWebsocket::get_outer_client(db.clone(), options)
+        .await
+        .unwrap();
+    let mut status_receiver = client.get_status_receiver().await;
+    client.get_outer_connect(db).await.unwrap();
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while status_receiver.recv().await != Some(SenderStatus::Connected) {}
+    })
+    .await
+    .unwrap();
+
+    let (authorization, _ws_stream) = server.await.unwrap();
+    assert_eq!(authorization.unwrap(), "Bearer token");
+    let response = client.upgrade_response().await.unwrap();
+    assert_eq!(response.subprotocol.as_deref(), Some("v2"));
+    assert_eq!(response.headers.get("x-server").unwrap(), "stand-in");
+
+    client.disconnect().await;
+    assert_eq!(client.upgrade_response().await, None);
+}
+
+#[tokio::test]
+async fn test_outer_failover() {
+    use crate::{
+        helpers::internal_server::{AtomicServer, ServerOptions},
+        test_db, AtomicWebsocket,
+    };
+    use tokio::net::TcpListener;
+
+    let unused = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let dead = format!("ws://{}", unused.local_addr().unwrap());
+    drop(unused);
+    let server = AtomicServer::new("127.0.0.1:0", ServerOptions::default(), None)
+        .await
+        .unwrap();
+    let live = format!("ws://{}", server.local_addr());
+    let db = test_db();
+    let options = ClientOptions {
+        url: dead.clone(),
+        fallback_urls: vec![live.clone()],
+        ..Default::default()
+    };
+
+    let client = AtomicWebsocket::get_outer_client(db.clone(), options.clone())
+        .await
+        .unwrap();
+    let mut status_receiver = client.get_status_receiver().await;
+    client.get_outer_connect(db.clone()).await.unwrap();
+    let mut statuses = Vec::new();
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while let Some(status) = status_receiver.recv().await {
+            let is_connected = status == SenderStatus::Connected;
+            statuses.push(status);
+            if is_connected {
+                break;
+            }
+        }
+    })
+    .await
+    .unwrap();
+    assert!(matches!(
+        &statuses[..],
+        [
+            SenderStatus::Start,
+            SenderStatus::Connecting { target: first },
+            SenderStatus::Error(_),
+            SenderStatus::Failover { from, to },
+            SenderStatus::Connecting { target: second },
+            SenderStatus::Connected,
+        ] if *first == dead && *from == dead && *to == live && *second == live
+    ));
+    let endpoints = client.endpoints().await;
+    assert_eq!((endpoints[0].failures, endpoints[1].failures), (1, 0));
+    assert!(endpoints[1].last_connected.is_some());
+    client.disconnect().await;
+    drop(client);
+
+    // The endpoint that worked is tried first from now on.
+    let client = AtomicWebsocket::get_outer_client(db.clone(), options)
+        .await
+        .unwrap();
+    let mut status_receiver = client.get_status_receiver().await;
+    client.get_outer_connect(db).await.unwrap();
+    let target = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            if let Some(SenderStatus::Connecting { target }) = status_receiver.recv().await {
+                break target;
+            }
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(target, live);
+}
+
+#[cfg(any(feature = "native_tls", feature = "rustls"))]
+#[tokio::test]
+async fn test_internal_client_tls() {
+    use crate::{
+        helpers::{
+            internal_server::{AtomicServer, ServerOptions},
+            tls::{CertificatePin, TlsIdentity},
+        },
+        test_db, AtomicWebsocket,
+    };
+
+    let certified = rcgen::generate_simple_self_signed(vec!["127.0.0.1".into()]).unwrap();
+    let cert = certified.cert.pem().into_bytes();
+    let group = std::net::UdpSocket::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let discovery = DiscoveryOptions {
+        group,
+        interval_seconds: 1,
+        timeout_seconds: 3,
+        ..Default::default()
+    };
+    let option = ServerOptions {
+        tls_identity: Some(TlsIdentity::Pem {
+            cert: cert.clone(),
+            key: certified.key_pair.serialize_pem().into_bytes(),
+        }),
+        discovery: Some(discovery.clone()),
+        ..Default::default()
+    };
+    let server = AtomicServer::new("127.0.0.1:0", option, None)
+        .await
+        .unwrap();
+    let target = format!("wss://{}", server.local_addr());
+    let port = server.local_addr().port().to_string();
+    let trusted = ClientTlsOptions {
+        root_certificates: vec![cert],
+        use_default_roots: false,
+        ..Default::default()
+    };
+    let pinned = ClientTlsOptions {
+        accept_invalid_certs: true,
+        pins: vec![CertificatePin::from_certificate_public_key(certified.cert.der()).unwrap()],
+        ..Default::default()
+    };
+
+    let cases = [
+        (
+            "",
+            ClientOptions {
+                scan: ScanOptions {
+                    ips: vec!["127.0.0.1".parse().unwrap()],
+                    use_tls: true,
+                    ..Default::default()
+                },
+                tls: trusted,
+                ..Default::default()
+            },
+        ),
+        (
+            "",
+            ClientOptions {
+                discovery: Some(discovery),
+                tls: pinned.clone(),
+                ..Default::default()
+            },
+        ),
+        (
+            target.as_str(),
+            ClientOptions {
+                tls: pinned,
+                ..Default::default()
+            },
+        ),
+    ];
+    for (server_ip, options) in cases {
+        let db = test_db();
+        let client = AtomicWebsocket::get_internal_client(db.clone(), options)
+            .await
+            .unwrap();
+        let mut status_receiver = client.get_status_receiver().await;
+        client
+            .get_internal_connect(
+                Some(ServerConnectInfo {
+                    server_ip,
+                    port: &port,
+                }),
+                db.clone(),
+            )
+            .await
+            .unwrap();
+
+        let mut targets = Vec::new();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                match status_receiver.recv().await {
+                    Some(SenderStatus::Connecting { target }) => targets.push(target),
+                    Some(SenderStatus::Connected) => break,
+                    _ => {}
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(targets, std::slice::from_ref(&target));
+        let saved = get_setting_by_key(db, format!("{:?}", SaveKey::ServerConnectInfo))
+            .await
+            .unwrap()
+            .unwrap();
+        let saved = ServerConnectInfo::deserialize(&saved.value).unwrap();
+        assert_eq!(saved.server_ip, target);
+        assert_eq!(saved.port, port);
+    }
 }
@@ -1,42 +1,89 @@
-use std::{net::SocketAddr, sync::Arc, time::Duration};
+use std::{
+    net::SocketAddr,
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
 
 use tokio::{
     self,
+    io::{AsyncRead, AsyncWrite},
     net::{TcpListener, TcpStream},
-    sync::{mpsc::Receiver, RwLock},
-    time::{Instant, MissedTickBehavior},
+    sync::{mpsc::Receiver, watch, RwLock},
+    task::{JoinHandle, JoinSet},
+    time::{timeout, Instant, MissedTickBehavior},
 };
 use tokio_tungstenite::{
-    tungstenite::{protocol::frame::Payload, Error},
+    tungstenite::{
+        handshake::server::{Callback, ErrorResponse, Request, Response},
+        http::{header::SEC_WEBSOCKET_PROTOCOL, HeaderValue, StatusCode},
+        protocol::frame::Payload,
+    },
     WebSocketStream,
 };
 
 use crate::{
     helpers::{
-        client_sender::ClientSendersTrait,
-        common::{get_data_schema, make_disconnect_message, make_pong_message},
+        auth::{AuthDecision, Authenticator, HandshakeRequest},
+        client_sender::{register_session, ClientSendersTrait, DisconnectReason, ServerEvent},
+        common::{
+            get_data_schema, make_disconnect_message, make_pong_message, make_rejected_message,
+        },
+        discovery::{Announcer, DiscoveryOptions},
+        error::{AtomicWebsocketError, Result},
+        tls::ServerTls,
+        traits::StringUtil,
+        upgrade::{UpgradeFilter, UpgradeRequest},
     },
     log_debug, log_error,
-    schema::{Category, Ping},
+    schema::{Category, Data, Handshake, Ping},
 };
 use bebop::Record;
-use futures_util::{stream::SplitStream, SinkExt, StreamExt};
+use futures_util::{future::join_all, stream::SplitStream, SinkExt, StreamExt};
 use tokio::sync::mpsc::{self, Sender};
-use tokio_tungstenite::{
-    accept_async,
-    tungstenite::{self, Message},
+use tokio_tungstenite::{accept_hdr_async, tungstenite::Message};
+
+use super::{
+    channel::{BackpressurePolicy, ChannelOptions, DroppedMessages},
+    client_sender::ClientSenders,
+    router::Router,
 };
 
-use super::client_sender::ClientSenders;
+#[cfg(any(feature = "native_tls", feature = "rustls"))]
+use super::tls::TlsIdentity;
 
+#[must_use = "dropping the server stops accepting and closes every session"]
 pub struct AtomicServer {
     pub client_senders: Arc<RwLock<ClientSenders>>,
+    local_addr: SocketAddr,
+    shutdown_tx: watch::Sender<bool>,
+    accept_handle: Option<JoinHandle<JoinSet<()>>>,
+    checker_handle: Option<JoinHandle<()>>,
+    discovery_handle: Option<JoinHandle<()>>,
 }
 
 #[derive(Clone)]
 pub struct ServerOptions {
     pub use_ping: bool,
     pub proxy_ping: i16,
+    pub check_interval_seconds: u64,
+    /// Should be longer than the clients' `ping_interval_seconds`.
+    pub idle_timeout_seconds: u64,
+    /// Used only when the server creates its own `ClientSenders`.
+    pub handle_message_channel: ChannelOptions,
+    /// Used only when the server creates its own `ClientSenders`.
+    pub event_channel: ChannelOptions,
+    pub outbound_channel: ChannelOptions,
+    /// Checks the first `Ping`/`Handshake` of every session; `None` trusts the claimed peer id.
+    pub authenticator: Option<Authenticator>,
+    /// Accepted subprotocols in order of preference.
+    pub subprotocols: Vec<String>,
+    /// Checks the HTTP upgrade; `None` accepts every upgrade.
+    pub upgrade_filter: Option<UpgradeFilter>,
+    /// Announces the server over UDP for clients with `ClientOptions::discovery`.
+    pub discovery: Option<DiscoveryOptions>,
+    /// Serves `wss://` with this identity; `None` serves plain `ws://`.
+    #[cfg(any(feature = "native_tls", feature = "rustls"))]
+    pub tls_identity: Option<TlsIdentity>,
 }
 
 impl Default for ServerOptions {
@@ -44,6 +91,17 @@ impl Default for ServerOptions {
         Self {
             use_ping: true,
             proxy_ping: -1,
+            check_interval_seconds: 15,
+            idle_timeout_seconds: 60,
+            handle_message_channel: ChannelOptions::new(1024, BackpressurePolicy::Block),
+            event_channel: ChannelOptions::new(64, BackpressurePolicy::DropNewest),
+            outbound_channel: ChannelOptions::new(8, BackpressurePolicy::Block),
+            authenticator: None,
+            subprotocols: Vec::new(),
+            upgrade_filter: None,
+            discovery: None,
+            #[cfg(any(feature = "native_tls", feature = "rustls"))]
+            tls_identity: None,
         }
     }
 }
@@ -53,61 +111,181 @@ impl AtomicServer {
         addr: &str,
         option: ServerOptions,
         client_senders: Option<Arc<RwLock<ClientSenders>>>,
-    ) -> Self {
-        let listener = TcpListener::bind(&addr).await.expect("Can't listen");
+    ) -> Result<Self> {
+        let tls = ServerTls::new(&option)?;
+        let listener = TcpListener::bind(&addr).await?;
+        let local_addr = listener.local_addr()?;
+        log_debug!("Listening on: {}", local_addr);
+        let announcer = match option.discovery.as_ref() {
+            Some(discovery) => Some(Announcer::bind(
+                discovery,
+                local_addr.port(),
+                !matches!(tls, ServerTls::Plain),
+            )?),
+            None => None,
+        };
         let client_senders = match client_senders {
             Some(client_senders) => client_senders,
-            None => Arc::new(RwLock::new(ClientSenders::new())),
+            None => Arc::new(RwLock::new(ClientSenders::with_channels(
+                option.handle_message_channel,
+                option.event_channel,
+            ))),
+        };
+        client_senders
+            .write()
+            .await
+            .set_outbound_channel(option.outbound_channel);
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let discovery_handle = announcer.map(|announcer| tokio::spawn(announcer.run()));
+        let checker_handle =
+            tokio::spawn(loop_client_checker(client_senders.clone(), option.clone()));
+        let accept_handle = tokio::spawn(handle_accept(
+            listener,
+            client_senders.clone(),
+            option,
+            tls,
+            shutdown_rx,
+        ));
+
+        Ok(Self {
+            client_senders,
+            local_addr,
+            shutdown_tx,
+            accept_handle: Some(accept_handle),
+            checker_handle: Some(checker_handle),
+            discovery_handle,
+        })
+    }
+
+    /// Stops accepting, sends `Disconnect` to every peer and waits up to
+    /// `drain_timeout` for their outbound queues to flush before aborting
+    /// whatever is still running.
+    pub async fn shutdown(mut self, drain_timeout: Duration) {
+        let _ = self.shutdown_tx.send(true);
+        if let Some(checker_handle) = self.checker_handle.take() {
+            checker_handle.abort();
+        }
+        if let Some(discovery_handle) = self.discovery_handle.take() {
+            discovery_handle.abort();
+        }
+        let mut connections = match self.accept_handle.take() {
+            Some(accept_handle) => accept_handle.await.unwrap_or_default(),
+            None => JoinSet::new(),
         };
-        tokio::spawn(handle_accept(listener, client_senders.clone(), option));
 
-        tokio::spawn(loop_client_checker(client_senders.clone()));
-        Self { client_senders }
+        // A peer with a full outbound queue must not hold shutdown past the deadline.
+        let drained = timeout(drain_timeout, async {
+            self.client_senders.disconnect_all().await;
+            while connections.join_next().await.is_some() {}
+        })
+        .await;
+        if drained.is_err() {
+            log_debug!(
+                "Abort {} connections after drain timeout",
+                connections.len()
+            );
+        }
+        connections.shutdown().await;
+        log_debug!("Server {} shutdown", self.local_addr);
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub async fn dropped_messages(&self) -> DroppedMessages {
+        self.client_senders.read().await.dropped_messages()
     }
 
     pub async fn get_handle_message_receiver(&self) -> Receiver<(Vec<u8>, String)> {
         self.client_senders.get_handle_message_receiver().await
     }
+
+    pub async fn get_event_receiver(&self) -> Receiver<ServerEvent> {
+        self.client_senders.get_event_receiver().await
+    }
+
+    /// Takes the handle message receiver and dispatches it through `router`.
+    pub async fn route(&self, router: Router) -> JoinHandle<()> {
+        tokio::spawn(router.run(self.get_handle_message_receiver().await))
+    }
 }
 
-pub async fn loop_client_checker(server_sender: Arc<RwLock<ClientSenders>>) {
-    let mut interval = tokio::time::interval_at(
-        Instant::now() + Duration::from_secs(15),
-        Duration::from_secs(15),
-    );
+pub async fn loop_client_checker(server_sender: Arc<RwLock<ClientSenders>>, option: ServerOptions) {
+    let check_interval = Duration::from_secs(option.check_interval_seconds.max(1));
+    let mut interval = tokio::time::interval_at(Instant::now() + check_interval, check_interval);
     interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
 
     loop {
         interval.tick().await;
-        server_sender.write().await.check_client_send_time();
+        let idle_clients = server_sender
+            .write()
+            .await
+            .take_idle_clients(option.idle_timeout_seconds);
+        let server_sender = &server_sender;
+        join_all(idle_clients.into_iter().map(|(peer, sx)| async move {
+            log_debug!("Close idle peer: {:?}", peer);
+            server_sender
+                .send_event(ServerEvent::Evicted {
+                    peer: peer.copy_string(),
+                })
+                .await;
+            let _ = timeout(
+                Duration::from_secs(1),
+                sx.send(make_disconnect_message(&peer)),
+            )
+            .await;
+        }))
+        .await;
         log_debug!("loop client cheker finish");
     }
 }
 
+impl Drop for AtomicServer {
+    fn drop(&mut self) {
+        let _ = self.shutdown_tx.send(true);
+        if let Some(checker_handle) = self.checker_handle.take() {
+            checker_handle.abort();
+        }
+        if let Some(discovery_handle) = self.discovery_handle.take() {
+            discovery_handle.abort();
+        }
+        if let Some(accept_handle) = self.accept_handle.take() {
+            accept_handle.abort();
+        }
+    }
+}
+
 pub async fn handle_accept(
     listener: TcpListener,
     client_senders: Arc<RwLock<ClientSenders>>,
     option: ServerOptions,
-) {
+    tls: ServerTls,
+    mut shutdown_rx: watch::Receiver<bool>,
+) -> JoinSet<()> {
+    let mut connections = JoinSet::new();
     loop {
-        match listener.accept().await {
-            Ok((stream, _)) => {
-                let peer = stream
-                    .peer_addr()
-                    .expect("connected streams should have a peer address");
-                log_debug!("Peer address: {}", peer);
-                tokio::spawn(accept_connection(
-                    client_senders.clone(),
-                    peer,
-                    stream,
-                    option.clone(),
-                ));
-            }
-            Err(e) => {
-                log_error!("Error accepting connection: {:?}", e);
-            }
+        tokio::select! {
+            _ = shutdown_rx.wait_for(|is_shutdown| *is_shutdown) => break,
+            Some(_) = connections.join_next(), if !connections.is_empty() => {}
+            accepted = listener.accept() => match accepted {
+                Ok((stream, peer)) => {
+                    log_debug!("Peer address: {}", peer);
+                    connections.spawn(accept_connection(
+                        client_senders.clone(),
+                        peer,
+                        stream,
+                        option.clone(),
+                        tls.clone(),
+                    ));
+                }
+                Err(e) => {
+                    log_error!("Error accepting connection: {:?}", e);
+                }
+            },
         }
     }
+    connections
 }
 
 pub async fn accept_connection(
@@ -115,137 +293,681 @@ pub async fn accept_connection(
     peer: SocketAddr,
     stream: TcpStream,
     option: ServerOptions,
+    tls: ServerTls,
 ) {
-    if let Err(e) = handle_connection(client_senders, peer, stream, option).await {
+    if let Err(e) = handle_tls_connection(client_senders, peer, stream, option, tls).await {
         match e {
-            Error::ConnectionClosed | Error::Protocol(_) | Error::Utf8 => (),
+            AtomicWebsocketError::ChannelClosed | AtomicWebsocketError::Protocol(_) => (),
             err => log_error!("Error processing connection: {}", err),
         }
     }
 }
 
-pub async fn handle_connection(
+/// Runs the TLS handshake when configured, then the WebSocket upgrade.
+async fn handle_tls_connection(
     client_senders: Arc<RwLock<ClientSenders>>,
     peer: SocketAddr,
     stream: TcpStream,
     option: ServerOptions,
-) -> tungstenite::Result<()> {
-    match accept_async(stream).await {
+    tls: ServerTls,
+) -> Result<()> {
+    match tls {
+        ServerTls::Plain => handle_connection(client_senders, peer, stream, option).await,
+        #[cfg(all(feature = "native_tls", not(feature = "rustls")))]
+        ServerTls::NativeTls(acceptor) => {
+            let stream = acceptor.accept(stream).await?;
+            handle_connection(client_senders, peer, stream, option).await
+        }
+        #[cfg(feature = "rustls")]
+        ServerTls::Rustls(acceptor) => {
+            let stream = acceptor.accept(stream).await?;
+            handle_connection(client_senders, peer, stream, option).await
+        }
+    }
+}
+
+pub async fn handle_connection<S>(
+    client_senders: Arc<RwLock<ClientSenders>>,
+    peer: SocketAddr,
+    stream: S,
+    option: ServerOptions,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let mut upgrade = UpgradeRequest::default();
+    let mut refused = None;
+    let callback = UpgradeCallback {
+        option: &option,
+        upgrade: &mut upgrade,
+        refused: &mut refused,
+    };
+    match accept_hdr_async(stream, callback).await {
         Ok(ws_stream) => {
             log_debug!("New WebSocket connection: {}", peer);
-            let (mut ostream, mut istream) = ws_stream.split();
-
-            let (sx, mut rx) = mpsc::channel(8);
-            tokio::spawn(async move {
-                let use_ping = option.use_ping;
-                let id = get_id_from_first_message(
-                    &mut istream,
-                    client_senders.clone(),
-                    sx.clone(),
-                    option,
-                )
-                .await;
+            let (mut ostream, istream) = ws_stream.split();
 
-                match id {
-                    Some(id) => {
-                        while let Some(Ok(message)) = istream.next().await {
-                            if let Payload::Owned(value) = message.into_data() {
-                                let data = match get_data_schema(&value) {
-                                    Ok(data) => data,
-                                    Err(e) => {
-                                        log_error!("Error getting data schema: {:?}", e);
-                                        continue;
-                                    }
-                                };
-                                if data.category == Category::Ping as u16 && use_ping {
-                                    if let Ok(data) = Ping::deserialize(&data.datas) {
-                                        client_senders
-                                            .send(data.peer.into(), make_pong_message())
-                                            .await;
-                                        continue;
-                                    }
-                                }
-                                if data.category == Category::Disconnect as u16 {
-                                    let _ =
-                                        sx.send(make_disconnect_message(&peer.to_string())).await;
-                                    break;
+            let (sx, mut rx) = mpsc::channel(option.outbound_channel.capacity.max(1));
+            // Only the registry keeps the channel alive, so removing the peer closes it.
+            let weak_sx = sx.downgrade();
+            let peer_id = Arc::new(OnceLock::new());
+            // Held in a JoinSet so the reader is aborted together with this connection.
+            let mut reader = JoinSet::new();
+            reader.spawn(read_messages(
+                istream,
+                client_senders.clone(),
+                sx,
+                peer,
+                upgrade,
+                option,
+                peer_id.clone(),
+            ));
+
+            let reason = loop {
+                tokio::select! {
+                    message = rx.recv() => {
+                        let Some(message) = message else {
+                            break DisconnectReason::ServerClosed;
+                        };
+                        if let Err(e) = ostream.send(message.clone()).await {
+                            break DisconnectReason::Error(e.into());
+                        }
+                        if let Message::Binary(data) = message {
+                            let data = match get_data_schema(data.as_slice()) {
+                                Ok(data) => data,
+                                Err(e) => {
+                                    log_error!("Error getting data schema: {:?}", e);
+                                    break DisconnectReason::Error(e);
                                 }
-                                client_senders.send_handle_message(data, &id).await;
+                            };
+                            log_debug!("Server sending message: {:?}", data);
+                            if data.category == Category::Disconnect as u16
+                                || data.category == Category::Rejected as u16
+                            {
+                                break DisconnectReason::ServerClosed;
                             }
                         }
                     }
-                    None => {
-                        let _ = sx.send(make_disconnect_message(&peer.to_string())).await;
-                    }
-                }
-            });
-
-            while let Some(message) = rx.recv().await {
-                ostream.send(message.clone()).await?;
-                if let Payload::Owned(data) = message.into_data() {
-                    let data = match get_data_schema(&data) {
-                        Ok(data) => data,
-                        Err(e) => {
-                            log_error!("Error getting data schema: {:?}", e);
-                            rx.close();
-                            break;
+                    Some(result) = reader.join_next() => {
+                        let reason = result.unwrap_or(DisconnectReason::ConnectionClosed);
+                        if reason == DisconnectReason::ClientClosed {
+                            let _ = ostream.send(make_disconnect_message(&peer.to_string())).await;
                         }
-                    };
-                    log_debug!("Server sending message: {:?}", data);
-                    if data.category == Category::Disconnect as u16 {
-                        rx.close();
-                        break;
+                        break reason;
                     }
                 }
+            };
+            log_debug!("client: {} disconnected: {:?}", peer, reason);
+            rx.close();
+            reader.abort_all();
+            if let Some(id) = peer_id.get() {
+                if let Some(sx) = weak_sx.upgrade() {
+                    client_senders.write().await.remove_if_same(id, &sx);
+                }
+                // A replaced session is not reported, the peer is still connected.
+                if !client_senders.is_active(id).await {
+                    client_senders
+                        .send_event(ServerEvent::Disconnected {
+                            peer: id.copy_string(),
+                            reason,
+                        })
+                        .await;
+                }
             }
-            log_debug!("client: {} disconnected", peer);
-            ostream.flush().await?;
+            ostream.close().await?;
         }
         Err(e) => {
             log_debug!("Error accepting WebSocket connection: {:?}", e);
+            if let Some(status) = refused {
+                client_senders
+                    .send_event(ServerEvent::Rejected {
+                        addr: peer,
+                        reason: format!("upgrade refused with {status}"),
+                    })
+                    .await;
+            }
         }
     }
 
     Ok(())
 }
 
-async fn get_id_from_first_message(
-    istream: &mut SplitStream<WebSocketStream<TcpStream>>,
+/// Keeps the upgrade request for the session, negotiates the subprotocol
+/// and runs the upgrade filter.
+struct UpgradeCallback<'a> {
+    option: &'a ServerOptions,
+    upgrade: &'a mut UpgradeRequest,
+    refused: &'a mut Option<StatusCode>,
+}
+
+impl Callback for UpgradeCallback<'_> {
+    fn on_request(
+        self,
+        request: &Request,
+        mut response: Response,
+    ) -> std::result::Result<Response, ErrorResponse> {
+        let mut upgrade = UpgradeRequest::new(request);
+        upgrade.negotiate(&self.option.subprotocols);
+        if let Some(filter) = &self.option.upgrade_filter {
+            if let Err(status) = filter.check(&upgrade) {
+                *self.refused = Some(status);
+                let mut error = ErrorResponse::new(None);
+                *error.status_mut() = status;
+                return Err(error);
+            }
+        }
+        if let Some(subprotocol) = &upgrade.subprotocol {
+            if let Ok(value) = HeaderValue::from_str(subprotocol) {
+                response.headers_mut().insert(SEC_WEBSOCKET_PROTOCOL, value);
+            }
+        }
+        *self.upgrade = upgrade;
+        Ok(response)
+    }
+}
+
+async fn read_messages<S>(
+    mut istream: SplitStream<WebSocketStream<S>>,
     client_senders: Arc<RwLock<ClientSenders>>,
     sx: Sender<Message>,
-    options: ServerOptions,
-) -> Option<String> {
-    let mut _id: Option<String> = None;
-    if let Some(Ok(message)) = istream.next().await {
-        log_debug!("receive first message from client: {:?}", message);
+    addr: SocketAddr,
+    upgrade: UpgradeRequest,
+    option: ServerOptions,
+    peer_id: Arc<OnceLock<String>>,
+) -> DisconnectReason
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let use_ping = option.use_ping;
+    let weak_sx = sx.downgrade();
+    let Some(id) = get_id_from_first_message(
+        &mut istream,
+        client_senders.clone(),
+        sx,
+        addr,
+        upgrade,
+        option,
+    )
+    .await
+    else {
+        log_debug!("No ping from {}, close", addr);
+        return DisconnectReason::ConnectionClosed;
+    };
+    let _ = peer_id.set(id.copy_string());
+
+    loop {
+        let message = match istream.next().await {
+            Some(Ok(message)) => message,
+            Some(Err(e)) => return DisconnectReason::Error(e.into()),
+            None => return DisconnectReason::ConnectionClosed,
+        };
+        client_senders.read().await.write_received_time(&id);
         if let Payload::Owned(value) = message.into_data() {
-            let mut data = match get_data_schema(&value) {
+            let data = match get_data_schema(&value) {
                 Ok(data) => data,
                 Err(e) => {
                     log_error!("Error getting data schema: {:?}", e);
-                    return None;
+                    continue;
                 }
             };
-            if data.category == Category::Ping as u16 {
-                log_debug!("receive ping from client: {:?}", data);
-                if let Ok(ping) = Ping::deserialize(&data.datas) {
-                    _id = Some(ping.peer.into());
-                    client_senders.add(&_id.as_ref().unwrap(), sx).await;
-                    if options.use_ping {
-                        client_senders
-                            .send(&_id.as_ref().unwrap(), make_pong_message())
-                            .await;
-                    } else {
-                        if options.proxy_ping > 0 {
-                            data.category = options.proxy_ping as u16;
-                        }
-                        client_senders
-                            .send_handle_message(data, &_id.as_ref().unwrap())
-                            .await;
+            // Answered by session, the id in the ping is not trusted.
+            if data.category == Category::Ping as u16
+                && use_ping
+                && Ping::deserialize(&data.datas).is_ok()
+            {
+                if let Err(error) = client_senders.send(&id, make_pong_message()).await {
+                    log_error!("Error sending pong: {:?}", error);
+                }
+                continue;
+            }
+            if data.category == Category::RpcResponse as u16 {
+                if let Err(error) = client_senders
+                    .write()
+                    .await
+                    .resolve_response(&id, &data.datas)
+                {
+                    log_error!("Error resolving response: {:?}", error);
+                }
+                continue;
+            }
+            if data.category == Category::Disconnect as u16 {
+                return DisconnectReason::ClientClosed;
+            }
+            match client_senders.send_handle_message(data, &id).await {
+                Ok(_) => {}
+                Err(AtomicWebsocketError::ChannelFull) => {
+                    log_error!("Handle message channel full, disconnect");
+                    if let Some(sx) = weak_sx.upgrade() {
+                        client_senders.write().await.remove_if_same(&id, &sx);
                     }
+                    return DisconnectReason::Backpressure;
                 }
+                Err(error) => log_error!("Error handling message: {:?}", error),
+            }
+        }
+    }
+}
+
+async fn get_id_from_first_message<S>(
+    istream: &mut SplitStream<WebSocketStream<S>>,
+    client_senders: Arc<RwLock<ClientSenders>>,
+    sx: Sender<Message>,
+    addr: SocketAddr,
+    upgrade: UpgradeRequest,
+    options: ServerOptions,
+) -> Option<String>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let Some(Ok(message)) = istream.next().await else {
+        return None;
+    };
+    log_debug!("receive first message from client: {:?}", message);
+    let Payload::Owned(value) = message.into_data() else {
+        return None;
+    };
+    let mut data = match get_data_schema(&value) {
+        Ok(data) => data,
+        Err(e) => {
+            log_error!("Error getting data schema: {:?}", e);
+            return None;
+        }
+    };
+    let request = match get_handshake_request(&data, addr, upgrade.clone()) {
+        Some(request) => request,
+        None => {
+            log_debug!("Unexpected first message from {}: {:?}", addr, data);
+            return None;
+        }
+    };
+    let peer = match authenticate(options.authenticator.as_ref(), request).await {
+        AuthDecision::Accept { peer } => peer,
+        AuthDecision::Reject { reason } => {
+            log_debug!("Reject {}: {}", addr, reason);
+            client_senders
+                .send_event(ServerEvent::Rejected {
+                    addr,
+                    reason: reason.copy_string(),
+                })
+                .await;
+            if sx.send(make_rejected_message(&reason)).await.is_ok() {
+                // Keeps the session open until `Rejected` is written.
+                sx.closed().await;
             }
+            return None;
         }
+    };
+
+    register_session(&client_senders, &peer, sx, Some(upgrade)).await;
+    client_senders
+        .send_event(ServerEvent::Connected {
+            peer: peer.copy_string(),
+            addr,
+        })
+        .await;
+    let result = if options.use_ping {
+        client_senders.send(&peer, make_pong_message()).await
+    } else {
+        if options.proxy_ping > 0 {
+            data.category = options.proxy_ping as u16;
+        }
+        client_senders.send_handle_message(data, &peer).await
+    };
+    if let Err(error) = result {
+        log_error!("Error answering first message: {:?}", error);
+    }
+    Some(peer)
+}
+
+fn get_handshake_request(
+    data: &Data<'_>,
+    addr: SocketAddr,
+    upgrade: UpgradeRequest,
+) -> Option<HandshakeRequest> {
+    let (peer, credentials) = if data.category == Category::Ping as u16 {
+        (Ping::deserialize(&data.datas).ok()?.peer, Vec::new())
+    } else if data.category == Category::Handshake as u16 {
+        let handshake = Handshake::deserialize(&data.datas).ok()?;
+        (handshake.peer, handshake.credentials.to_vec())
+    } else {
+        return None;
+    };
+    Some(HandshakeRequest {
+        peer: peer.into(),
+        credentials,
+        upgrade,
+        addr,
+    })
+}
+
+async fn authenticate(
+    authenticator: Option<&Authenticator>,
+    request: HandshakeRequest,
+) -> AuthDecision {
+    match authenticator {
+        Some(authenticator) => authenticator.authenticate(request).await,
+        None => AuthDecision::Accept { peer: request.peer },
+    }
+}
+
+#[tokio::test]
+async fn test_bind_ephemeral_port() {
+    let first = AtomicServer::new("127.0.0.1:0", ServerOptions::default(), None)
+        .await
+        .unwrap();
+    let second = AtomicServer::new("127.0.0.1:0", ServerOptions::default(), None)
+        .await
+        .unwrap();
+    assert_ne!(first.local_addr().port(), 0);
+    assert_ne!(first.local_addr(), second.local_addr());
+
+    let taken = AtomicServer::new(
+        &first.local_addr().to_string(),
+        ServerOptions::default(),
+        None,
+    )
+    .await;
+    assert!(matches!(taken, Err(AtomicWebsocketError::Io(_))));
+}
+
+#[tokio::test]
+async fn test_shutdown_disconnects_peers() {
+    use crate::helpers::common::make_ping_message;
+    use tokio_tungstenite::connect_async;
+
+    let server = AtomicServer::new("127.0.0.1:0", ServerOptions::default(), None)
+        .await
+        .unwrap();
+    let url = format!("ws://{}", server.local_addr());
+    let (mut ws_stream, _) = connect_async(&url).await.unwrap();
+    ws_stream.send(make_ping_message("peer")).await.unwrap();
+    let pong = ws_stream.next().await.unwrap().unwrap().into_data();
+    assert_eq!(
+        get_data_schema(pong.as_slice()).unwrap().category,
+        Category::Pong as u16
+    );
+
+    timeout(
+        Duration::from_secs(3),
+        server.shutdown(Duration::from_secs(1)),
+    )
+    .await
+    .unwrap();
+    let disconnect = ws_stream.next().await.unwrap().unwrap().into_data();
+    assert_eq!(
+        get_data_schema(disconnect.as_slice()).unwrap().category,
+        Category::Disconnect as u16
+    );
+    assert!(matches!(
+        ws_stream.next().await,
+        Some(Ok(Message::Close(_))) | None
+    ));
+    assert!(connect_async(&url).await.is_err());
+}
+
+#[tokio::test]
+async fn test_shutdown_with_stalled_peer() {
+    let server = AtomicServer::new("127.0.0.1:0", ServerOptions::default(), None)
+        .await
+        .unwrap();
+    let (sx, _rx) = mpsc::channel(1);
+    sx.send(make_pong_message()).await.unwrap();
+    server.client_senders.write().await.add("stalled", sx).await;
+
+    timeout(
+        Duration::from_secs(3),
+        server.shutdown(Duration::from_millis(500)),
+    )
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn test_idle_peer_is_closed() {
+    use crate::helpers::common::make_ping_message;
+    use tokio_tungstenite::connect_async;
+
+    let option = ServerOptions {
+        check_interval_seconds: 1,
+        idle_timeout_seconds: 1,
+        ..Default::default()
+    };
+    let server = AtomicServer::new("127.0.0.1:0", option, None)
+        .await
+        .unwrap();
+    let url = format!("ws://{}", server.local_addr());
+    let (mut ws_stream, _) = connect_async(&url).await.unwrap();
+    ws_stream.send(make_ping_message("peer")).await.unwrap();
+    ws_stream.next().await.unwrap().unwrap();
+    assert!(server.client_senders.is_active("peer").await);
+
+    let disconnect = timeout(Duration::from_secs(5), ws_stream.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap()
+        .into_data();
+    assert_eq!(
+        get_data_schema(disconnect.as_slice()).unwrap().category,
+        Category::Disconnect as u16
+    );
+    assert!(!server.client_senders.is_active("peer").await);
+}
+
+#[tokio::test]
+async fn test_server_events() {
+    use crate::helpers::common::make_ping_message;
+    use tokio_tungstenite::connect_async;
+
+    let server = AtomicServer::new("127.0.0.1:0", ServerOptions::default(), None)
+        .await
+        .unwrap();
+    let mut events = server.get_event_receiver().await;
+    let url = format!("ws://{}", server.local_addr());
+    let mut sessions = Vec::new();
+    for _ in 0..2 {
+        let (mut ws_stream, _) = connect_async(&url).await.unwrap();
+        ws_stream.send(make_ping_message("peer")).await.unwrap();
+        ws_stream.next().await.unwrap().unwrap();
+        sessions.push(ws_stream);
+    }
+    assert!(matches!(
+        events.recv().await,
+        Some(ServerEvent::Connected { peer, .. }) if peer == "peer"
+    ));
+    assert_eq!(
+        events.recv().await,
+        Some(ServerEvent::Replaced {
+            peer: "peer".into()
+        })
+    );
+    assert!(matches!(
+        events.recv().await,
+        Some(ServerEvent::Connected { .. })
+    ));
+
+    sessions[1]
+        .send(make_disconnect_message("peer"))
+        .await
+        .unwrap();
+    assert_eq!(
+        timeout(Duration::from_secs(3), events.recv())
+            .await
+            .unwrap(),
+        Some(ServerEvent::Disconnected {
+            peer: "peer".into(),
+            reason: DisconnectReason::ClientClosed
+        })
+    );
+    assert!(!server.client_senders.is_active("peer").await);
+}
+
+#[tokio::test]
+async fn test_authenticator() {
+    use crate::helpers::common::{make_handshake_message, make_ping_message};
+    use crate::schema::Rejected;
+    use tokio_tungstenite::{connect_async, tungstenite::client::IntoClientRequest};
+
+    let option = ServerOptions {
+        authenticator: Some(Authenticator::new(|request: HandshakeRequest| async move {
+            let is_lan = request.upgrade.headers.contains_key("x-lan");
+            match (request.credentials.as_slice(), is_lan) {
+                (b"secret", true) => AuthDecision::Accept {
+                    peer: format!("user-{}", request.peer),
+                },
+                _ => AuthDecision::Reject {
+                    reason: "bad credentials".into(),
+                },
+            }
+        })),
+        ..Default::default()
+    };
+    let server = AtomicServer::new("127.0.0.1:0", option, None)
+        .await
+        .unwrap();
+    let mut events = server.get_event_receiver().await;
+    let url = format!("ws://{}", server.local_addr());
+    let mut request = url.as_str().into_client_request().unwrap();
+    request.headers_mut().insert("x-lan", "1".parse().unwrap());
+
+    for first_message in [
+        make_ping_message("peer"),
+        make_handshake_message("peer", b"wrong"),
+    ] {
+        let (mut ws_stream, _) = connect_async(request.clone()).await.unwrap();
+        ws_stream.send(first_message).await.unwrap();
+        let rejected = ws_stream.next().await.unwrap().unwrap().into_data();
+        let data = get_data_schema(rejected.as_slice()).unwrap();
+        assert_eq!(data.category, Category::Rejected as u16);
+        assert_eq!(
+            Rejected::deserialize(&data.datas).unwrap().reason,
+            "bad credentials"
+        );
+        assert!(matches!(
+            events.recv().await,
+            Some(ServerEvent::Rejected { reason, .. }) if reason == "bad credentials"
+        ));
     }
-    _id
+
+    let (mut ws_stream, _) = connect_async(&url).await.unwrap();
+    ws_stream
+        .send(make_handshake_message("peer", b"secret"))
+        .await
+        .unwrap();
+    let rejected = ws_stream.next().await.unwrap().unwrap().into_data();
+    assert_eq!(
+        get_data_schema(rejected.as_slice()).unwrap().category,
+        Category::Rejected as u16
+    );
+
+    let (mut ws_stream, _) = connect_async(request).await.unwrap();
+    ws_stream
+        .send(make_handshake_message("peer", b"secret"))
+        .await
+        .unwrap();
+    let pong = ws_stream.next().await.unwrap().unwrap().into_data();
+    assert_eq!(
+        get_data_schema(pong.as_slice()).unwrap().category,
+        Category::Pong as u16
+    );
+    assert!(server.client_senders.is_active("user-peer").await);
+    assert!(!server.client_senders.is_active("peer").await);
+}
+
+#[cfg(all(feature = "native_tls", not(feature = "rustls")))]
+#[tokio::test]
+async fn test_tls_server() {
+    use crate::helpers::common::make_ping_message;
+    use tokio_tungstenite::{connect_async, connect_async_tls_with_config, Connector};
+
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+    let cert = certified.cert.pem();
+    let option = ServerOptions {
+        tls_identity: Some(TlsIdentity::Pem {
+            cert: cert.clone().into_bytes(),
+            key: certified.key_pair.serialize_pem().into_bytes(),
+        }),
+        ..Default::default()
+    };
+    let server = AtomicServer::new("127.0.0.1:0", option, None)
+        .await
+        .unwrap();
+    let port = server.local_addr().port();
+
+    let connector = native_tls::TlsConnector::builder()
+        .add_root_certificate(native_tls::Certificate::from_pem(cert.as_bytes()).unwrap())
+        .build()
+        .unwrap();
+    let (mut ws_stream, _) = connect_async_tls_with_config(
+        format!("wss://localhost:{}", port),
+        None,
+        false,
+        Some(Connector::NativeTls(connector)),
+    )
+    .await
+    .unwrap();
+    ws_stream.send(make_ping_message("peer")).await.unwrap();
+    let pong = ws_stream.next().await.unwrap().unwrap().into_data();
+    assert_eq!(
+        get_data_schema(pong.as_slice()).unwrap().category,
+        Category::Pong as u16
+    );
+    assert!(server.client_senders.is_active("peer").await);
+
+    assert!(connect_async(format!("ws://localhost:{}", port))
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn test_upgrade_request() {
+    use crate::helpers::common::make_ping_message;
+    use tokio_tungstenite::{
+        connect_async,
+        tungstenite::{client::IntoClientRequest, Error},
+    };
+
+    let option = ServerOptions {
+        subprotocols: vec!["v2".into(), "v1".into()],
+        upgrade_filter: Some(UpgradeFilter::new(|request| {
+            match request.origin() == Some("https://app.example") {
+                true => Ok(()),
+                false => Err(StatusCode::FORBIDDEN),
+            }
+        })),
+        ..Default::default()
+    };
+    let server = AtomicServer::new("127.0.0.1:0", option, None)
+        .await
+        .unwrap();
+    let mut events = server.get_event_receiver().await;
+    let url = format!("ws://{}/ws?room=1", server.local_addr());
+
+    match connect_async(&url).await {
+        Err(Error::Http(response)) => assert_eq!(response.status(), StatusCode::FORBIDDEN),
+        other => panic!("upgrade without origin accepted: {other:?}"),
+    }
+    assert!(matches!(
+        events.recv().await,
+        Some(ServerEvent::Rejected { reason, .. }) if reason.contains("403")
+    ));
+
+    let mut request = url.as_str().into_client_request().unwrap();
+    let headers = request.headers_mut();
+    headers.insert("origin", HeaderValue::from_static("https://app.example"));
+    headers.insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static("v1, v2"));
+    let (mut ws_stream, response) = connect_async(request).await.unwrap();
+    assert_eq!(
+        response.headers().get(SEC_WEBSOCKET_PROTOCOL).unwrap(),
+        "v2"
+    );
+    ws_stream.send(make_ping_message("peer")).await.unwrap();
+    ws_stream.next().await.unwrap().unwrap();
+
+    let upgrade = server.client_senders.upgrade_request("peer").await.unwrap();
+    assert_eq!(upgrade.path, "/ws");
+    assert_eq!(upgrade.query.as_deref(), Some("room=1"));
+    assert_eq!(upgrade.origin(), Some("https://app.example"));
+    assert_eq!(upgrade.subprotocols, vec!["v1", "v2"]);
+    assert_eq!(upgrade.subprotocol.as_deref(), Some("v2"));
 }
@@ -1,9 +1,19 @@
+pub mod auth;
+pub mod channel;
 pub mod client_sender;
 pub mod common;
+pub mod discovery;
+pub mod endpoints;
+pub mod error;
 pub mod get_internal_websocket;
 pub mod get_outer_websocket;
 pub mod internal_client;
 pub mod internal_server;
+pub mod network;
+pub mod router;
+pub mod rpc;
 pub mod scan_manager;
 pub mod server_sender;
+pub mod tls;
 pub mod traits;
+pub mod upgrade;
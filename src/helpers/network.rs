@@ -0,0 +1,86 @@
+use std::net::Ipv4Addr;
+
+use if_addrs::{get_if_addrs, IfAddr, Interface};
+use ipnet::Ipv4Net;
+
+/// An IPv4 address of a local interface with the network it belongs to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LocalNetwork {
+    pub interface: String,
+    pub ip: Ipv4Addr,
+    pub network: Ipv4Net,
+}
+
+impl LocalNetwork {
+    fn from_interface(interface: Interface) -> Option<Self> {
+        let IfAddr::V4(addr) = interface.addr else {
+            return None;
+        };
+        Some(Self {
+            interface: interface.name,
+            ip: addr.ip,
+            network: Ipv4Net::new(addr.ip, addr.prefixlen).ok()?.trunc(),
+        })
+    }
+}
+
+/// The IPv4 networks of the local interfaces, without loopback and
+/// link-local addresses. Needs no route to the internet.
+pub fn local_networks() -> Vec<LocalNetwork> {
+    let interfaces = match get_if_addrs() {
+        Ok(interfaces) => interfaces,
+        Err(_) => return Vec::new(),
+    };
+    interfaces
+        .into_iter()
+        .filter(|interface| !interface.is_loopback() && !interface.is_link_local())
+        .filter_map(LocalNetwork::from_interface)
+        .collect()
+}
+
+/// The address of `preferred_interface` when it has one, else of the first
+/// interface, else `127.0.0.1` when `loopback_fallback` is set.
+pub fn local_ip_address(
+    preferred_interface: Option<&str>,
+    loopback_fallback: bool,
+) -> Option<Ipv4Addr> {
+    pick_ip_address(&local_networks(), preferred_interface, loopback_fallback)
+}
+
+fn pick_ip_address(
+    local_networks: &[LocalNetwork],
+    preferred_interface: Option<&str>,
+    loopback_fallback: bool,
+) -> Option<Ipv4Addr> {
+    local_networks
+        .iter()
+        .find(|local| Some(local.interface.as_str()) == preferred_interface)
+        .or(local_networks.first())
+        .map(|local| local.ip)
+        .or(loopback_fallback.then_some(Ipv4Addr::LOCALHOST))
+}
+
+#[test]
+fn test_pick_ip_address() {
+    let local = |interface: &str, ip: &str| LocalNetwork {
+        interface: interface.into(),
+        ip: ip.parse().unwrap(),
+        network: format!("{ip}/24").parse::<Ipv4Net>().unwrap().trunc(),
+    };
+    let local_networks = [local("eth0", "10.0.0.5"), local("wlan0", "192.168.1.20")];
+
+    assert_eq!(
+        pick_ip_address(&local_networks, None, false),
+        Some("10.0.0.5".parse().unwrap())
+    );
+    assert_eq!(
+        pick_ip_address(&local_networks, Some("wlan0"), false),
+        Some("192.168.1.20".parse().unwrap())
+    );
+    assert_eq!(
+        pick_ip_address(&local_networks, Some("missing"), false),
+        Some("10.0.0.5".parse().unwrap())
+    );
+    assert_eq!(pick_ip_address(&[], None, true), Some(Ipv4Addr::LOCALHOST));
+    assert_eq!(pick_ip_address(&[], None, false), None);
+}
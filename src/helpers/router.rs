@@ -0,0 +1,244 @@
+use std::{collections::HashMap, future::Future, pin::Pin, sync::Arc};
+
+use bebop::Record;
+use tokio::{
+    sync::{mpsc::Receiver, Semaphore},
+    task::{JoinError, JoinSet},
+};
+
+use crate::{
+    log_debug, log_error,
+    schema::{Category, Data, RpcRequest},
+};
+
+type HandlerFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+type Handler = Arc<dyn Fn(RouteMessage) -> HandlerFuture + Send + Sync>;
+
+/// A decoded incoming message. `peer` is empty on the client side.
+#[derive(Debug, Clone)]
+pub struct RouteMessage {
+    pub category: u16,
+    pub datas: Vec<u8>,
+    pub peer: String,
+    /// Set when the message came in an `RpcRequest`; `category` and `datas`
+    /// are then the inner ones. Answer with `respond` and this id.
+    pub rpc_id: Option<u32>,
+}
+
+impl RouteMessage {
+    fn decode(datas: &[u8], peer: String) -> super::error::Result<Self> {
+        let data = Data::deserialize(datas)?;
+        if data.category != Category::RpcRequest as u16 {
+            return Ok(Self {
+                category: data.category,
+                datas: data.datas.to_vec(),
+                peer,
+                rpc_id: None,
+            });
+        }
+        let rpc = RpcRequest::deserialize(&data.datas)?;
+        Ok(Self {
+            category: rpc.category,
+            datas: rpc.datas.to_vec(),
+            peer,
+            rpc_id: Some(rpc.id),
+        })
+    }
+}
+
+/// Items of the handle message channels: `(datas, peer)` on the server and
+/// `datas` on the client.
+pub trait RouteInput: Send {
+    fn into_parts(self) -> (Vec<u8>, String);
+}
+
+impl RouteInput for (Vec<u8>, String) {
+    fn into_parts(self) -> (Vec<u8>, String) {
+        self
+    }
+}
+
+impl RouteInput for Vec<u8> {
+    fn into_parts(self) -> (Vec<u8>, String) {
+        (self, String::new())
+    }
+}
+
+/// Dispatches incoming messages to async handlers registered per category.
+pub struct Router {
+    handlers: HashMap<u16, Handler>,
+    fallback: Option<Handler>,
+    concurrency: usize,
+}
+
+impl Default for Router {
+    fn default() -> Self {
+        Self {
+            handlers: HashMap::new(),
+            fallback: None,
+            concurrency: 16,
+        }
+    }
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Maximum number of handlers running at the same time.
+    pub fn concurrency(mut self, limit: usize) -> Self {
+        self.concurrency = limit.max(1);
+        self
+    }
+
+    pub fn route<F, Fut>(mut self, category: u16, handler: F) -> Self
+    where
+        F: Fn(RouteMessage) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.handlers.insert(category, wrap_handler(handler));
+        self
+    }
+
+    /// Called for categories without a registered handler.
+    pub fn fallback<F, Fut>(mut self, handler: F) -> Self
+    where
+        F: Fn(RouteMessage) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.fallback = Some(wrap_handler(handler));
+        self
+    }
+
+    pub async fn run<T: RouteInput>(self, mut rx: Receiver<T>) {
+        let semaphore = Arc::new(Semaphore::new(self.concurrency));
+        let mut tasks = JoinSet::new();
+        loop {
+            tokio::select! {
+                Some(result) = tasks.join_next(), if !tasks.is_empty() => log_panic(result),
+                received = rx.recv() => {
+                    let Some((datas, peer)) = received.map(RouteInput::into_parts) else {
+                        break;
+                    };
+                    let message = match RouteMessage::decode(&datas, peer) {
+                        Ok(message) => message,
+                        Err(e) => {
+                            log_error!("Error getting data schema: {:?}", e);
+                            continue;
+                        }
+                    };
+                    let handler = match self
+                        .handlers
+                        .get(&message.category)
+                        .or(self.fallback.as_ref())
+                    {
+                        Some(handler) => handler.clone(),
+                        None => {
+                            log_debug!("No handler for category: {}", message.category);
+                            continue;
+                        }
+                    };
+                    let Ok(permit) = semaphore.clone().acquire_owned().await else {
+                        break;
+                    };
+                    tasks.spawn(async move {
+                        let _permit = permit;
+                        handler(message).await;
+                    });
+                }
+            }
+        }
+        while let Some(result) = tasks.join_next().await {
+            log_panic(result);
+        }
+    }
+}
+
+fn wrap_handler<F, Fut>(handler: F) -> Handler
+where
+    F: Fn(RouteMessage) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    Arc::new(move |message| Box::pin(handler(message)))
+}
+
+fn log_panic(result: Result<(), JoinError>) {
+    if let Err(e) = result {
+        log_error!("Handler failed: {:?}", e);
+    }
+}
+
+#[tokio::test]
+async fn test_router_dispatch() {
+    use tokio::sync::mpsc;
+
+    let (handled_sx, mut handled_rx) = mpsc::channel(8);
+    let fallback_sx = handled_sx.clone();
+    let router = Router::new()
+        .concurrency(2)
+        .route(1, |_| async { panic!("handler panic") })
+        .route(2, move |message| {
+            let handled_sx = handled_sx.clone();
+            async move {
+                let _ = handled_sx.send((message.category, message.peer)).await;
+            }
+        })
+        .fallback(move |message| {
+            let fallback_sx = fallback_sx.clone();
+            async move {
+                let _ = fallback_sx.send((message.category, message.peer)).await;
+            }
+        });
+
+    let (sx, rx) = mpsc::channel(8);
+    let handle = tokio::spawn(router.run(rx));
+    for category in [1, 2, 3] {
+        let mut buf = Vec::new();
+        Data {
+            category,
+            datas: bebop::SliceWrapper::from_raw(&[]),
+        }
+        .serialize(&mut buf)
+        .unwrap();
+        sx.send((buf, "peer".into())).await.unwrap();
+    }
+    let mut handled = vec![
+        handled_rx.recv().await.unwrap(),
+        handled_rx.recv().await.unwrap(),
+    ];
+    handled.sort();
+    assert_eq!(handled, vec![(2, "peer".into()), (3, "peer".into())]);
+
+    drop(sx);
+    handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn test_router_unwraps_rpc_request() {
+    use crate::helpers::common::make_rpc_request_message;
+    use tokio::sync::mpsc;
+
+    let (handled_sx, mut handled_rx) = mpsc::channel(8);
+    let router = Router::new().route(7, move |message| {
+        let handled_sx = handled_sx.clone();
+        async move {
+            let _ = handled_sx.send((message.rpc_id, message.datas)).await;
+        }
+    });
+
+    let (sx, rx) = mpsc::channel(8);
+    let handle = tokio::spawn(router.run(rx));
+    let message = make_rpc_request_message(42, 7, b"hello").into_data();
+    let data = crate::helpers::common::get_data_schema(message.as_slice()).unwrap();
+    let mut buf = Vec::new();
+    data.serialize(&mut buf).unwrap();
+    sx.send(buf).await.unwrap();
+    assert_eq!(
+        handled_rx.recv().await.unwrap(),
+        (Some(42), b"hello".to_vec())
+    );
+
+    drop(sx);
+    handle.await.unwrap();
+}
@@ -0,0 +1,80 @@
+use std::{collections::HashMap, time::Duration};
+
+use bebop::Record;
+use tokio::{sync::oneshot, time::timeout};
+
+use crate::{log_debug, schema::RpcResponse};
+
+use super::error::{AtomicWebsocketError, Result};
+
+type ResponseSender = oneshot::Sender<Result<Vec<u8>>>;
+
+/// Requests waiting for an `RpcResponse`, keyed by correlation id.
+#[derive(Default)]
+pub struct PendingRequests {
+    next_id: u32,
+    lists: HashMap<u32, (String, ResponseSender)>,
+}
+
+impl PendingRequests {
+    pub fn register(&mut self, peer: &str) -> (u32, oneshot::Receiver<Result<Vec<u8>>>) {
+        let (sx, rx) = oneshot::channel();
+        loop {
+            self.next_id = self.next_id.wrapping_add(1);
+            if !self.lists.contains_key(&self.next_id) {
+                break;
+            }
+        }
+        self.lists.insert(self.next_id, (peer.to_string(), sx));
+        (self.next_id, rx)
+    }
+
+    pub fn resolve(&mut self, peer: &str, datas: &[u8]) -> Result<()> {
+        let response = RpcResponse::deserialize(datas)?;
+        let is_matched = matches!(
+            self.lists.get(&response.id),
+            Some((request_peer, _)) if request_peer == peer
+        );
+        match is_matched
+            .then(|| self.lists.remove(&response.id))
+            .flatten()
+        {
+            Some((_, sx)) => {
+                let result = match response.success {
+                    true => Ok(response.datas.to_vec()),
+                    false => Err(AtomicWebsocketError::Remote(
+                        String::from_utf8_lossy(&response.datas).into(),
+                    )),
+                };
+                let _ = sx.send(result);
+            }
+            None => log_debug!("Unknown rpc response: {}", response.id),
+        }
+        Ok(())
+    }
+
+    pub fn cancel(&mut self, id: u32) {
+        self.lists.remove(&id);
+    }
+
+    /// Dropping the senders fails the waiting requests with `ChannelClosed`.
+    pub fn cancel_peer(&mut self, peer: &str) {
+        self.lists
+            .retain(|_, (request_peer, _)| request_peer != peer);
+    }
+
+    pub fn cancel_all(&mut self) {
+        self.lists.clear();
+    }
+}
+
+pub async fn wait_response(
+    rx: oneshot::Receiver<Result<Vec<u8>>>,
+    duration: Duration,
+) -> Result<Vec<u8>> {
+    match timeout(duration, rx).await {
+        Ok(Ok(result)) => result,
+        Ok(Err(_)) => Err(AtomicWebsocketError::ChannelClosed),
+        Err(_) => Err(AtomicWebsocketError::Timeout),
+    }
+}
@@ -1,18 +1,115 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr};
 use std::sync::Arc;
 use std::time::Duration;
 
+use futures_util::{future::join_all, SinkExt};
+use ipnet::Ipv4Net;
 use tokio::net::TcpStream;
-use tokio::sync::RwLock;
-use tokio::time::{timeout, MissedTickBehavior};
-use tokio_tungstenite::{connect_async, tungstenite, MaybeTlsStream, WebSocketStream};
+use tokio::sync::{watch, RwLock, Semaphore};
+use tokio::task::JoinSet;
+use tokio::time::{sleep_until, timeout, Instant, MissedTickBehavior};
+use tokio_tungstenite::{tungstenite::client::IntoClientRequest, MaybeTlsStream, WebSocketStream};
 
 use crate::helpers::traits::connection_state::ConnectionManager;
 use crate::log_debug;
-use crate::server_sender::get_ip_address;
 
+use super::common::make_disconnect_message;
+use super::error::{AtomicWebsocketError, Result};
+use super::internal_client::ClientOptions;
+use super::network::{local_ip_address, local_networks, LocalNetwork};
 use super::traits::StringUtil;
 
+#[derive(Clone, Debug)]
+pub struct ScanOptions {
+    /// Scanned instead of the local interface networks when this or `ips` is not empty.
+    pub networks: Vec<Ipv4Net>,
+    pub ips: Vec<IpAddr>,
+    /// Connection attempts running at the same time.
+    pub concurrency: usize,
+    /// Interface networks wider than this are narrowed around the local address.
+    pub min_prefix_len: u8,
+    /// Its networks are scanned first and its address is used as the local ip.
+    pub preferred_interface: Option<String>,
+    /// Scans `127.0.0.1` when no interface has an IPv4 address.
+    pub loopback_fallback: bool,
+    /// The scan fails with `Timeout` when no server answered within this; 0 scans until cancelled.
+    pub timeout_seconds: u64,
+    /// Scans `wss://` through `ClientOptions::tls`, for a server with a `tls_identity`.
+    #[cfg(any(feature = "native_tls", feature = "rustls"))]
+    pub use_tls: bool,
+}
+
+impl Default for ScanOptions {
+    fn default() -> Self {
+        Self {
+            networks: Vec::new(),
+            ips: Vec::new(),
+            concurrency: 64,
+            min_prefix_len: 22,
+            preferred_interface: None,
+            loopback_fallback: true,
+            timeout_seconds: 60,
+            #[cfg(any(feature = "native_tls", feature = "rustls"))]
+            use_tls: false,
+        }
+    }
+}
+
+impl ScanOptions {
+    pub fn local_ip_address(&self) -> Option<Ipv4Addr> {
+        local_ip_address(self.preferred_interface.as_deref(), self.loopback_fallback)
+    }
+
+    /// `server_ip` with `wss://` when `use_tls` is set and `ws://` otherwise,
+    /// unless it already has a scheme. Empty stays empty.
+    pub(crate) fn url(&self, server_ip: &str) -> String {
+        if server_ip.is_empty() || server_ip.contains("://") {
+            return server_ip.into();
+        }
+        #[cfg(any(feature = "native_tls", feature = "rustls"))]
+        if self.use_tls {
+            return format!("wss://{}", server_ip);
+        }
+        format!("ws://{}", server_ip)
+    }
+
+    /// Hosts to scan in order and without duplicates. The local addresses are
+    /// included, the server may run on the same machine.
+    fn hosts(&self, local_networks: &[LocalNetwork]) -> Vec<IpAddr> {
+        let is_explicit = !self.networks.is_empty() || !self.ips.is_empty();
+        if !is_explicit && local_networks.is_empty() && self.loopback_fallback {
+            return vec![IpAddr::V4(Ipv4Addr::LOCALHOST)];
+        }
+        let mut local_networks = local_networks.to_vec();
+        local_networks.sort_by_key(|local| {
+            Some(local.interface.as_str()) != self.preferred_interface.as_deref()
+        });
+        let networks = match is_explicit {
+            false => local_networks
+                .iter()
+                .map(
+                    |local| match local.network.prefix_len() < self.min_prefix_len {
+                        true => Ipv4Net::new(local.ip, self.min_prefix_len)
+                            .map(|network| network.trunc())
+                            .unwrap_or(local.network),
+                        false => local.network,
+                    },
+                )
+                .collect(),
+            true => self.networks.clone(),
+        };
+        let mut seen = HashSet::new();
+        networks
+            .iter()
+            .flat_map(|network| network.hosts())
+            .map(IpAddr::V4)
+            .chain(self.ips.iter().copied())
+            .filter(|ip| seen.insert(*ip))
+            .collect()
+    }
+}
+
 pub struct ConnectionState {
     pub status: WebSocketStatus,
     pub is_connecting: bool,
@@ -20,41 +117,36 @@ pub struct ConnectionState {
 }
 
 pub struct ScanManager {
+    options: Arc<ClientOptions>,
     scan_ips: Vec<String>,
     connection_states: Arc<RwLock<HashMap<String, ConnectionState>>>,
+    permits: Arc<Semaphore>,
+    checks: JoinSet<()>,
+    deadline: Option<Duration>,
 }
 
 impl ScanManager {
-    pub fn new(port: &str) -> Self {
-        let mut scan_ips = Vec::new();
-        let ip = get_ip_address();
-        let ips = ip.split('.').collect::<Vec<&str>>();
-
-        for sub_ip in 1..255 {
-            let ip = format!("ws://{}.{}.{}.{}:{}", ips[0], ips[1], ips[2], sub_ip, port);
-            scan_ips.push(ip);
-        }
+    pub fn new(port: &str, options: &ClientOptions) -> Self {
+        let scan = &options.scan;
+        let scan_ips = scan
+            .hosts(&local_networks())
+            .into_iter()
+            .map(|ip| match ip {
+                IpAddr::V4(ip) => scan.url(&format!("{}:{}", ip, port)),
+                IpAddr::V6(ip) => scan.url(&format!("[{}]:{}", ip, port)),
+            })
+            .collect();
 
         Self {
+            options: Arc::new(options.clone()),
             scan_ips,
             connection_states: Arc::new(RwLock::new(HashMap::new())),
+            permits: Arc::new(Semaphore::new(scan.concurrency.max(1))),
+            checks: JoinSet::new(),
+            deadline: (scan.timeout_seconds > 0).then(|| Duration::from_secs(scan.timeout_seconds)),
         }
     }
 
-    async fn is_connecting_allowed(&self, server_ip: &str) -> bool {
-        // 이미 연결 시도 중인지 확인
-        if let Some(state) = self.connection_states.read().await.get(server_ip) {
-            if state.is_connecting {
-                return false;
-            }
-        }
-        // 연결된 상태인지 확인
-        if self.connection_states.is_connected().await {
-            return false;
-        }
-        true
-    }
-
     async fn get_scannable_ips(&self) -> Vec<String> {
         let states = self.connection_states.read().await;
 
@@ -73,70 +165,138 @@ impl ScanManager {
     }
 
     async fn scan_network(&mut self) {
+        while self.checks.try_join_next().is_some() {}
+        // Checked once per tick, `run` picks the connected server up on the next one.
+        if self.connection_states.is_connected().await {
+            return;
+        }
         let scan_list: Vec<String> = self.get_scannable_ips().await;
 
         for server_ip in scan_list {
-            if !self.is_connecting_allowed(&server_ip).await {
-                continue;
-            }
-
+            let options = self.options.clone();
             let connection_states = self.connection_states.clone();
+            let permits = self.permits.clone();
             let server_ip = server_ip.clone();
-            tokio::spawn(async move {
-                connection_states.start_connection(&server_ip).await;
-                let status = check_connection(server_ip.copy_string()).await;
+            // Marked before waiting for a permit so the next tick does not queue it again.
+            connection_states.start_connection(&server_ip).await;
+            self.checks.spawn(async move {
+                let Ok(_permit) = permits.acquire_owned().await else {
+                    return;
+                };
+                let status = check_connection(&options, server_ip.copy_string()).await;
                 log_debug!("server_ip: {}, {:?}", server_ip, status);
                 connection_states.end_connection(&server_ip, status).await;
             });
         }
     }
 
-    pub async fn run(&mut self) -> (String, WebSocketStream<MaybeTlsStream<TcpStream>>) {
+    /// Scans every 2 seconds until a server answers. Returns `None` when
+    /// cancelled and `Timeout` once `ScanOptions::timeout_seconds` passed.
+    pub async fn run(
+        &mut self,
+        cancel: ScanCancel,
+    ) -> Result<Option<(String, WebSocketStream<MaybeTlsStream<TcpStream>>)>> {
         let mut interval =
             tokio::time::interval_at(tokio::time::Instant::now(), Duration::from_secs(2));
         interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let deadline = self.deadline.map(|deadline| Instant::now() + deadline);
 
-        loop {
-            interval.tick().await;
+        let result = loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = cancel.cancelled() => break Ok(None),
+                _ = sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                    break Err(AtomicWebsocketError::Timeout);
+                }
+            }
             if let Some(state) = self.connection_states.get_connected_ip().await {
-                return state;
+                break Ok(Some(state));
             }
             self.scan_network().await;
+        };
+        self.checks.abort_all();
+        self.close_extra_connections().await;
+        result
+    }
+
+    /// Other servers that answered are told the client is not staying.
+    async fn close_extra_connections(&mut self) {
+        let extras: Vec<_> = self
+            .connection_states
+            .write()
+            .await
+            .drain()
+            .filter_map(|(server_ip, state)| Some((server_ip, state.ws_stream?)))
+            .collect();
+        join_all(
+            extras
+                .into_iter()
+                .map(|(server_ip, mut ws_stream)| async move {
+                    log_debug!("Close extra scan connection: {}", server_ip);
+                    let _ = timeout(Duration::from_secs(1), async {
+                        let _ = ws_stream.send(make_disconnect_message(&server_ip)).await;
+                        let _ = ws_stream.close(None).await;
+                    })
+                    .await;
+                }),
+        )
+        .await;
+    }
+}
+
+/// Stops a running scan; clones cancel the same scan.
+#[derive(Clone)]
+pub(crate) struct ScanCancel {
+    is_cancelled: Arc<watch::Sender<bool>>,
+}
+
+impl ScanCancel {
+    pub(crate) fn new() -> Self {
+        Self {
+            is_cancelled: Arc::new(watch::Sender::new(false)),
         }
     }
+
+    pub(crate) fn cancel(&self) {
+        self.is_cancelled.send_replace(true);
+    }
+
+    pub(crate) fn is_cancelled(&self) -> bool {
+        *self.is_cancelled.borrow()
+    }
+
+    pub(crate) async fn cancelled(&self) {
+        let _ = self
+            .is_cancelled
+            .subscribe()
+            .wait_for(|is_cancelled| *is_cancelled)
+            .await;
+    }
 }
 
 async fn check_connection(
+    options: &ClientOptions,
     server_ip: String,
 ) -> (
     WebSocketStatus,
     Option<WebSocketStream<MaybeTlsStream<TcpStream>>>,
 ) {
-    match timeout(Duration::from_secs(10), connect_async(&server_ip)).await {
-        Ok(result) => match result {
-            Ok((ws_stream, _)) => {
-                // 연결 성공
-                (WebSocketStatus::Connected, Some(ws_stream))
-            }
-
-            Err(e) => {
-                match e {
-                    tungstenite::Error::Io(e) => match e.kind() {
-                        std::io::ErrorKind::ConnectionRefused => {
-                            // 포트는 닫혔지만 호스트는 존재
-                            (WebSocketStatus::ConnectionRefused, None)
-                        }
-                        _ => {
-                            // 그 외 에러 (호스트가 없거나 네트워크 문제)
-                            (WebSocketStatus::Timeout, None)
-                        }
-                    },
-                    _ => (WebSocketStatus::Timeout, None),
-                }
-            }
-        },
-        Err(_) => {
-            // 타임아웃
+    let connect = async {
+        options
+            .connect(server_ip.as_str().into_client_request()?)
+            .await
+    };
+    match timeout(Duration::from_secs(10), connect).await {
+        Ok(Ok((ws_stream, _))) => {
+            // 연결 성공
+            (WebSocketStatus::Connected, Some(ws_stream))
+        }
+        Ok(Err(AtomicWebsocketError::Io(_))) => {
+            // 포트가 닫혔거나 호스트에 닿지 않음
+            (WebSocketStatus::ConnectionRefused, None)
+        }
+        _ => {
+            // 타임아웃 또는 WebSocket/TLS 핸드셰이크 실패
             (WebSocketStatus::Timeout, None)
         }
     }
@@ -149,3 +309,170 @@ pub enum WebSocketStatus {
     ConnectionRefused,
     Timeout,
 }
+
+#[test]
+fn test_scan_hosts() {
+    let local = |interface: &str, ip: &str, prefix_len: u8| {
+        let ip: Ipv4Addr = ip.parse().unwrap();
+        LocalNetwork {
+            interface: interface.into(),
+            ip,
+            network: Ipv4Net::new(ip, prefix_len).unwrap().trunc(),
+        }
+    };
+    let local_networks = [
+        local("eth0", "10.0.4.7", 22),
+        local("wlan0", "192.168.1.20", 24),
+        local("vpn0", "172.16.9.5", 12),
+    ];
+
+    let hosts = ScanOptions::default().hosts(&local_networks);
+    assert_eq!(hosts.len(), 1022 + 254 + 1022);
+    assert_eq!(hosts[0], "10.0.4.1".parse::<IpAddr>().unwrap());
+    assert!(hosts.contains(&"10.0.7.254".parse().unwrap()));
+    assert!(hosts.contains(&"10.0.4.7".parse().unwrap()));
+    assert!(hosts.contains(&"172.16.11.254".parse().unwrap()));
+    assert!(!hosts.contains(&"172.16.12.1".parse().unwrap()));
+
+    let options = ScanOptions {
+        networks: vec!["10.1.0.0/30".parse().unwrap()],
+        ips: vec!["10.1.0.1".parse().unwrap(), "::1".parse().unwrap()],
+        ..Default::default()
+    };
+    let expected: Vec<IpAddr> = ["10.1.0.1", "10.1.0.2", "::1"]
+        .iter()
+        .map(|ip| ip.parse().unwrap())
+        .collect();
+    assert_eq!(options.hosts(&local_networks), expected);
+
+    let options = ScanOptions {
+        preferred_interface: Some("wlan0".into()),
+        ..Default::default()
+    };
+    assert_eq!(
+        options.hosts(&local_networks)[0],
+        "192.168.1.1".parse::<IpAddr>().unwrap()
+    );
+    assert_eq!(
+        ScanOptions::default().hosts(&[]),
+        vec![IpAddr::V4(Ipv4Addr::LOCALHOST)]
+    );
+    let options = ScanOptions {
+        loopback_fallback: false,
+        ..Default::default()
+    };
+    assert!(options.hosts(&[]).is_empty());
+}
+
+#[cfg(test)]
+fn client_options(scan: &ScanOptions) -> ClientOptions {
+    ClientOptions {
+        scan: scan.clone(),
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn test_scan_explicit_ips() {
+    use crate::helpers::internal_server::{AtomicServer, ServerOptions};
+
+    let server = AtomicServer::new("127.0.0.1:0", ServerOptions::default(), None)
+        .await
+        .unwrap();
+    let options = ScanOptions {
+        ips: vec!["127.0.0.1".parse().unwrap()],
+        ..Default::default()
+    };
+    let port = server.local_addr().port().to_string();
+    let scanned = ScanManager::new(&port, &client_options(&options))
+        .run(ScanCancel::new())
+        .await
+        .unwrap();
+    assert_eq!(
+        scanned.map(|(server_ip, _)| server_ip),
+        Some(format!("ws://{}", server.local_addr()))
+    );
+}
+
+#[tokio::test]
+async fn test_scan_cancel_and_deadline() {
+    let unused = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = unused.local_addr().unwrap().port().to_string();
+    drop(unused);
+    let options = ScanOptions {
+        ips: vec!["127.0.0.1".parse().unwrap()],
+        timeout_seconds: 1,
+        ..Default::default()
+    };
+
+    let started = Instant::now();
+    let scanned = ScanManager::new(&port, &client_options(&options))
+        .run(ScanCancel::new())
+        .await;
+    assert!(matches!(scanned, Err(AtomicWebsocketError::Timeout)));
+    assert!(started.elapsed() < Duration::from_secs(2));
+
+    let cancel = ScanCancel::new();
+    let scan = tokio::spawn({
+        let cancel = cancel.clone();
+        let options = ScanOptions {
+            timeout_seconds: 0,
+            ..options
+        };
+        async move {
+            ScanManager::new(&port, &client_options(&options))
+                .run(cancel)
+                .await
+        }
+    });
+    cancel.cancel();
+    let scanned = timeout(Duration::from_secs(1), scan)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(matches!(scanned, Ok(None)));
+}
+
+#[tokio::test]
+async fn test_scan_closes_extra_connections() {
+    use crate::{helpers::common::get_data_schema, schema::Category};
+    use futures_util::StreamExt;
+    use tokio::{net::TcpListener, sync::mpsc};
+
+    // Reachable as both 127.0.0.1 and 127.0.0.2.
+    let listener = TcpListener::bind("0.0.0.0:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port().to_string();
+    let (first_messages, mut first_message_rx) = mpsc::channel(4);
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let first_messages = first_messages.clone();
+            tokio::spawn(async move {
+                let mut ws_stream = tokio_tungstenite::accept_async(stream).await.unwrap();
+                if let Some(Ok(message)) = ws_stream.next().await {
+                    let _ = first_messages.send(message).await;
+                }
+            });
+        }
+    });
+    let options = ScanOptions {
+        ips: vec!["127.0.0.1".parse().unwrap(), "127.0.0.2".parse().unwrap()],
+        ..Default::default()
+    };
+
+    let scanned = ScanManager::new(&port, &client_options(&options))
+        .run(ScanCancel::new())
+        .await
+        .unwrap();
+    let (_server_ip, _ws_stream) = scanned.unwrap();
+    let message = timeout(Duration::from_secs(1), first_message_rx.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    let message = message.into_data();
+    let data = get_data_schema(message.as_slice()).unwrap();
+    assert_eq!(data.category, Category::Disconnect as u16);
+    assert!(timeout(Duration::from_millis(200), first_message_rx.recv())
+        .await
+        .is_err());
+}
@@ -1,49 +1,131 @@
-use std::{sync::Arc, time::Duration};
+use std::{
+    future::Future,
+    sync::{atomic::AtomicBool, Arc},
+    time::Duration,
+};
 
 use async_trait::async_trait;
 use bebop::Record;
 use native_db::Database;
 use tokio::{
     sync::{
-        mpsc::{self, Receiver, Sender},
+        mpsc::{self, Receiver},
         RwLock,
     },
+    task::JoinSet,
     time::sleep,
 };
-use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::tungstenite::{
+    handshake::client::Response,
+    http::{header::SEC_WEBSOCKET_PROTOCOL, HeaderMap, Uri},
+    Message,
+};
 
 use crate::{
     generated::schema::{Data, SaveKey, ServerConnectInfo},
     helpers::{
-        common::get_setting_by_key, get_internal_websocket::wrap_get_internal_websocket,
-        traits::StringUtil,
+        common::get_setting_by_key,
+        get_internal_websocket::spawn_internal_websocket,
+        traits::{atomic::FlagAtomic, StringUtil},
     },
     log_debug, log_error, Settings,
 };
 
 use crate::helpers::traits::date_time::now;
 
-use super::{common::make_disconnect_message, internal_client::ClientOptions};
+use super::{
+    channel::{channel, DropCounters, DroppedMessages, PolicySender},
+    client_sender::DisconnectReason,
+    common::{make_disconnect_message, make_rpc_request_message, make_rpc_response_message},
+    discovery::Announcement,
+    endpoints::Endpoints,
+    error::{AtomicWebsocketError, Result},
+    internal_client::ClientOptions,
+    rpc::{wait_response, PendingRequests},
+    scan_manager::ScanCancel,
+};
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum SenderStatus {
     Start,
+    /// Waiting for a UDP announcement of the server.
+    Discovering,
+    /// A server announced itself; the client connects to it next.
+    Discovered(Announcement),
+    /// Looking for the server on the local network.
+    Scanning,
+    Connecting {
+        target: String,
+    },
     Connected,
-    Disconnected,
+    /// Waiting `delay` before reconnect attempt number `attempt`.
+    Reconnecting {
+        attempt: u32,
+        delay: Duration,
+    },
+    /// The server expired this client.
+    Expired,
+    /// The outer client moved on to the next endpoint.
+    Failover {
+        from: String,
+        to: String,
+    },
+    Disconnected {
+        reason: DisconnectReason,
+    },
+    Error(AtomicWebsocketError),
+}
+
+/// What the server answered to the HTTP upgrade of the outer connection.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct UpgradeResponse {
+    /// The subprotocol the server picked from `ClientOptions::subprotocols`.
+    pub subprotocol: Option<String>,
+    pub headers: HeaderMap,
+}
+
+impl From<&Response> for UpgradeResponse {
+    fn from(response: &Response) -> Self {
+        Self {
+            subprotocol: response
+                .headers()
+                .get(SEC_WEBSOCKET_PROTOCOL)
+                .and_then(|value| value.to_str().ok())
+                .map(String::from),
+            headers: response.headers().clone(),
+        }
+    }
 }
 
 pub struct ServerSender {
-    sx: Option<mpsc::Sender<Message>>,
+    sx: Option<PolicySender<Message>>,
     pub db: Arc<RwLock<Database<'static>>>,
     pub server_sender: Option<Arc<RwLock<ServerSender>>>,
     pub server_ip: String,
     pub server_received_times: i64,
-    status_tx: Sender<SenderStatus>,
+    /// When the current session was added; the pong timeout runs from here until the first pong.
+    pub connected_times: i64,
+    /// Set while connected to an outer server.
+    pub upgrade_response: Option<UpgradeResponse>,
+    pub(crate) endpoints: Endpoints,
+    scan_cancel: Option<ScanCancel>,
+    status_tx: PolicySender<SenderStatus>,
     status_rx: Option<Receiver<SenderStatus>>,
-    handle_message_tx: Sender<Vec<u8>>,
+    handle_message_tx: PolicySender<Vec<u8>>,
     handle_message_rx: Option<Receiver<Vec<u8>>>,
+    dropped: DropCounters,
     pub options: ClientOptions,
     pub is_try_connect: bool,
+    /// Set when the server sent `Expired`; automatic reconnection stops until an explicit connect.
+    pub is_expired: bool,
+    pub is_closed: Arc<AtomicBool>,
+    /// Connects, delayed reconnects and pings of the sessions, aborted by `close`.
+    tasks: JoinSet<()>,
+    /// Bumped whenever another `AtomicClient` takes this sender over, so an
+    /// earlier client no longer closes it.
+    pub(crate) generation: u64,
+    pending_requests: PendingRequests,
+    reconnect_attempt: u32,
 }
 
 impl ServerSender {
@@ -52,8 +134,12 @@ impl ServerSender {
         server_ip: String,
         options: ClientOptions,
     ) -> Self {
-        let (status_tx, status_rx) = mpsc::channel(8);
-        let (handle_message_tx, handle_message_rx) = mpsc::channel(8);
+        let dropped = DropCounters::default();
+        let (status_tx, status_rx) = channel(options.status_channel, dropped.status.clone());
+        let (handle_message_tx, handle_message_rx) = channel(
+            options.handle_message_channel,
+            dropped.handle_message.clone(),
+        );
 
         Self {
             sx: None,
@@ -61,12 +147,23 @@ impl ServerSender {
             server_sender: None,
             server_ip,
             server_received_times: 0,
+            connected_times: 0,
+            upgrade_response: None,
+            endpoints: Endpoints::new(options.outer_urls()),
+            scan_cancel: None,
             status_tx,
             status_rx: Some(status_rx), // 초기에 저장
             handle_message_tx,
             handle_message_rx: Some(handle_message_rx),
+            dropped,
             options,
             is_try_connect: false,
+            is_expired: false,
+            is_closed: Arc::new(AtomicBool::new(false)),
+            tasks: JoinSet::new(),
+            generation: 0,
+            pending_requests: PendingRequests::default(),
+            reconnect_attempt: 0,
         }
     }
     pub fn get_status_receiver(&mut self) -> Receiver<SenderStatus> {
@@ -82,7 +179,7 @@ impl ServerSender {
     }
     fn sx_drop(&mut self) {
         if self.sx.is_some() {
-            let sender = self.sx.clone().unwrap();
+            let sender = self.sx.as_ref().unwrap().sender().clone();
             let prev_server_ip = self.server_ip.copy_string();
             tokio::spawn(async move {
                 let _ = sender.send(make_disconnect_message(&prev_server_ip)).await;
@@ -90,101 +187,129 @@ impl ServerSender {
             });
             self.sx = None;
         }
+        self.pending_requests.cancel_all();
     }
     pub fn add(&mut self, sx: mpsc::Sender<Message>, server_ip: &str) {
         self.sx_drop();
-        self.sx = Some(sx);
+        self.sx = Some(PolicySender::new(
+            sx,
+            self.options.outbound_channel,
+            self.dropped.outbound.clone(),
+        ));
         self.server_ip = server_ip.into();
+        self.connected_times = now().timestamp();
     }
     pub fn remove_ip(&mut self) {
         if !self.server_ip.is_empty() {
             self.sx_drop();
             self.server_ip = "".into();
+            self.upgrade_response = None;
         }
     }
-    pub fn send_status(&self, status: SenderStatus) {
-        let status_sx = self.status_tx.clone();
-        let _ = status_sx.try_send(status);
-    }
-    pub fn send_handle_message(&self, data: Vec<u8>) {
-        let handle_message_tx = self.handle_message_tx.clone();
-        let _ = handle_message_tx.try_send(data);
-    }
-    pub async fn send(&mut self, message: Message) {
-        if let Some(sx) = &self.sx {
-            let sender = sx.clone();
-            let mut backoff = Duration::from_millis(50); // 시작은 50ms로
-            let max_backoff = Duration::from_secs(1); // 최대 1초
-            let mut count = 0;
-
-            let limit_count = match self.options.retry_seconds > 5 {
-                true => 5,
-                false => match self.options.retry_seconds {
-                    0 | 1 => 1,
-                    _ => self.options.retry_seconds - 1,
-                },
-            };
-            match sender.send(message.clone()).await {
-                Ok(_) => {}
-                Err(e) => {
-                    log_error!("Error server sending message: {:?}", e);
-                    self.send_status(SenderStatus::Disconnected);
-
-                    loop {
-                        match sx.clone().send(message.clone()).await {
-                            Ok(_) => {
-                                break;
-                            }
-                            Err(e) => {
-                                if count > limit_count {
-                                    tokio::spawn(wrap_get_internal_websocket(
-                                        self.db.clone(),
-                                        self.server_sender.as_ref().unwrap().clone(),
-                                        self.server_ip.copy_string(),
-                                        self.options.clone(),
-                                    ));
-                                    break;
-                                }
-
-                                log_error!(
-                                    "Error sending message (attempt {}): {:?}",
-                                    count + 1,
-                                    e
-                                );
-                                count += 1;
-
-                                // Exponential backoff with max limit
-                                backoff = std::cmp::min(backoff * 2, max_backoff);
-                                sleep(backoff).await;
-                            }
-                        };
-                    }
-                }
+    /// Clears the session if `sx` is still the current one.
+    pub(crate) fn remove_if_same(&mut self, sx: &mpsc::Sender<Message>) -> bool {
+        match &self.sx {
+            Some(current) if current.same_channel(sx) => {
+                self.sx = None;
+                self.server_ip = "".into();
+                self.upgrade_response = None;
+                self.pending_requests.cancel_all();
+                true
             }
+            _ => false,
+        }
+    }
+    /// Marks a scan as running until `end_scan`.
+    pub(crate) fn start_scan(&mut self, cancel: ScanCancel) {
+        // Closed before the scan registered, nothing would cancel it later.
+        if self.is_closed.is_true() {
+            cancel.cancel();
+        }
+        self.is_try_connect = true;
+        self.scan_cancel = Some(cancel);
+    }
+    pub(crate) fn end_scan(&mut self) {
+        self.is_try_connect = false;
+        self.scan_cancel = None;
+    }
+    pub fn cancel_scan(&mut self) {
+        if let Some(cancel) = self.scan_cancel.take() {
+            cancel.cancel();
+        }
+    }
+    /// Runs `task` until `close`; nothing is started once the client is closed.
+    pub(crate) fn spawn<F>(&mut self, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        while self.tasks.try_join_next().is_some() {}
+        if !self.is_closed.is_true() {
+            self.tasks.spawn(task);
+        }
+    }
+    /// Stops the scan and every spawned task and drops the session.
+    pub(crate) fn close(&mut self) {
+        self.cancel_scan();
+        self.tasks.abort_all();
+        self.remove_ip();
+    }
+    pub(crate) fn next_reconnect_attempt(&mut self) -> u32 {
+        self.reconnect_attempt += 1;
+        self.reconnect_attempt
+    }
+    pub(crate) fn reset_reconnect_attempt(&mut self) {
+        self.reconnect_attempt = 0;
+    }
+    pub async fn send_status(&self, status: SenderStatus) {
+        let _ = self.status_tx.send(status).await;
+    }
+    pub fn dropped_messages(&self) -> DroppedMessages {
+        self.dropped.dropped_messages()
+    }
+    pub fn resolve_response(&mut self, datas: &[u8]) -> Result<()> {
+        self.pending_requests.resolve("", datas)
+    }
+    pub async fn send_handle_message(&self, data: Vec<u8>) -> Result<()> {
+        self.handle_message_tx.send(data).await
+    }
+    /// Send attempts after the first failure before reconnecting.
+    fn retry_limit(&self) -> u64 {
+        match self.options.retry_seconds > 5 {
+            true => 5,
+            false => match self.options.retry_seconds {
+                0 | 1 => 1,
+                _ => self.options.retry_seconds - 1,
+            },
         }
     }
 }
 
 #[async_trait]
 pub trait ServerSenderTrait {
-    async fn add(&self, sx: mpsc::Sender<Message>, server_ip: &str);
+    async fn add(&self, sx: mpsc::Sender<Message>, server_ip: &str) -> Result<()>;
     async fn send_status(&self, status: SenderStatus);
-    async fn send_handle_message(&self, data: Data<'_>);
+    async fn send_handle_message(&self, data: Data<'_>) -> Result<()>;
     async fn get_status_receiver(&self) -> Receiver<SenderStatus>;
     async fn get_handle_message_receiver(&self) -> Receiver<Vec<u8>>;
-    async fn send(&self, message: Message);
+    async fn send(&self, message: Message) -> Result<()>;
+    async fn request(&self, category: u16, datas: Vec<u8>, timeout: Duration) -> Result<Vec<u8>>;
+    async fn respond(&self, id: u32, datas: Vec<u8>) -> Result<()>;
     async fn regist(&mut self, server_sender: Arc<RwLock<ServerSender>>);
     async fn is_valid_server_ip(&self) -> bool;
     async fn remove_ip(&self);
-    async fn remove_ip_if_valid_server_ip(&self, server_ip: &str);
+    async fn remove_ip_if_valid_server_ip(&self, server_ip: &str) -> Result<()>;
     async fn write_received_times(&self);
 }
 
 #[async_trait]
 impl ServerSenderTrait for Arc<RwLock<ServerSender>> {
-    async fn add(&self, sx: mpsc::Sender<Message>, server_ip: &str) {
+    async fn add(&self, sx: mpsc::Sender<Message>, server_ip: &str) -> Result<()> {
         let mut clone = self.write().await;
-        clone.add(sx, server_ip.into());
+        // Checked under the lock, so a session either sees the close or is dropped by it.
+        if clone.is_closed.is_true() {
+            return Err(AtomicWebsocketError::Closed);
+        }
+        clone.add(sx, server_ip);
         let db = clone.db.clone();
         drop(clone);
 
@@ -199,44 +324,45 @@ impl ServerSenderTrait for Arc<RwLock<ServerSender>> {
                 }
             };
         let db = db.read().await;
-        let writer = db.rw_transaction().unwrap();
+        let writer = db.rw_transaction()?;
         match server_connect_info {
             Some(before_data) => {
                 let before_value = before_data.value.clone();
-                let mut data = ServerConnectInfo::deserialize(&before_value).unwrap();
+                let mut data = ServerConnectInfo::deserialize(&before_value)?;
 
-                data.server_ip = &server_ip;
+                data.server_ip = server_ip;
                 let mut value = Vec::new();
-                data.serialize(&mut value).unwrap();
+                data.serialize(&mut value)?;
 
-                writer.remove::<Settings>(before_data).unwrap();
-                writer
-                    .insert::<Settings>(Settings {
-                        key: format!("{:?}", SaveKey::ServerConnectInfo),
-                        value,
-                    })
-                    .unwrap();
+                writer.remove::<Settings>(before_data)?;
+                writer.insert::<Settings>(Settings {
+                    key: format!("{:?}", SaveKey::ServerConnectInfo),
+                    value,
+                })?;
             }
             None => {
                 let mut value = Vec::new();
+                // Only an explicit port is saved, like a port given to `get_internal_connect`.
+                let port = server_ip
+                    .parse::<Uri>()
+                    .ok()
+                    .and_then(|uri| uri.port_u16())
+                    .map(|port| port.to_string())
+                    .unwrap_or_default();
                 let data = ServerConnectInfo {
                     server_ip,
-                    port: match server_ip.contains(":") {
-                        true => server_ip.split(":").nth(1).unwrap(),
-                        false => "",
-                    },
+                    port: &port,
                 };
 
-                data.serialize(&mut value).unwrap();
-                writer
-                    .insert::<Settings>(Settings {
-                        key: format!("{:?}", SaveKey::ServerConnectInfo),
-                        value,
-                    })
-                    .unwrap();
+                data.serialize(&mut value)?;
+                writer.insert::<Settings>(Settings {
+                    key: format!("{:?}", SaveKey::ServerConnectInfo),
+                    value,
+                })?;
             }
         }
-        writer.commit().unwrap();
+        writer.commit()?;
+        Ok(())
     }
 
     async fn get_status_receiver(&self) -> Receiver<SenderStatus> {
@@ -248,17 +374,95 @@ impl ServerSenderTrait for Arc<RwLock<ServerSender>> {
     }
 
     async fn send_status(&self, status: SenderStatus) {
-        self.read().await.send_status(status);
+        let status_tx = self.read().await.status_tx.clone();
+        let _ = status_tx.send(status).await;
     }
 
-    async fn send_handle_message(&self, data: Data<'_>) {
+    async fn send_handle_message(&self, data: Data<'_>) -> Result<()> {
         let mut buf = Vec::new();
-        data.serialize(&mut buf).unwrap();
-        self.write().await.send_handle_message(buf);
+        data.serialize(&mut buf)?;
+        let handle_message_tx = self.read().await.handle_message_tx.clone();
+        handle_message_tx.send(buf).await
+    }
+
+    async fn send(&self, message: Message) -> Result<()> {
+        // Only the outbound sender is taken under the lock, so a full queue
+        // does not stall pings, responses or status readers.
+        let (sx, limit_count) = {
+            let clone = self.read().await;
+            match &clone.sx {
+                Some(sx) => (sx.clone(), clone.retry_limit()),
+                None => return Err(AtomicWebsocketError::NotConnected),
+            }
+        };
+        let mut backoff = Duration::from_millis(50); // 시작은 50ms로
+        let max_backoff = Duration::from_secs(1); // 최대 1초
+        let mut count = 0;
+
+        match sx.send(message.clone()).await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                log_error!("Error server sending message: {:?}", e);
+                if e == AtomicWebsocketError::ChannelFull {
+                    let mut clone = self.write().await;
+                    if clone
+                        .sx
+                        .as_ref()
+                        .is_some_and(|current| current.same_channel(sx.sender()))
+                    {
+                        clone.remove_ip();
+                    }
+                    drop(clone);
+                    self.send_status(SenderStatus::Disconnected {
+                        reason: DisconnectReason::Backpressure,
+                    })
+                    .await;
+                    return Err(e);
+                }
+                self.send_status(SenderStatus::Disconnected {
+                    reason: DisconnectReason::Error(e),
+                })
+                .await;
+
+                loop {
+                    match sx.send(message.clone()).await {
+                        Ok(_) => return Ok(()),
+                        Err(e) => {
+                            if count > limit_count {
+                                reconnect_after_send_failure(self).await;
+                                return Err(AtomicWebsocketError::ChannelClosed);
+                            }
+
+                            log_error!("Error sending message (attempt {}): {:?}", count + 1, e);
+                            count += 1;
+
+                            // Exponential backoff with max limit
+                            backoff = std::cmp::min(backoff * 2, max_backoff);
+                            sleep(backoff).await;
+                        }
+                    };
+                }
+            }
+        }
+    }
+
+    async fn request(&self, category: u16, datas: Vec<u8>, timeout: Duration) -> Result<Vec<u8>> {
+        let (id, rx) = self.write().await.pending_requests.register("");
+        let result = match self
+            .send(make_rpc_request_message(id, category, &datas))
+            .await
+        {
+            Ok(_) => wait_response(rx, timeout).await,
+            Err(error) => Err(error),
+        };
+        if result.is_err() {
+            self.write().await.pending_requests.cancel(id);
+        }
+        result
     }
 
-    async fn send(&self, message: Message) {
-        self.write().await.send(message).await;
+    async fn respond(&self, id: u32, datas: Vec<u8>) -> Result<()> {
+        self.send(make_rpc_response_message(id, &datas)).await
     }
 
     async fn regist(&mut self, server_sender: Arc<RwLock<ServerSender>>) {
@@ -268,11 +472,7 @@ impl ServerSenderTrait for Arc<RwLock<ServerSender>> {
     async fn is_valid_server_ip(&self) -> bool {
         let clone = self.read().await;
         let result = !clone.server_ip.is_empty()
-            && clone.server_received_times
-                + (match clone.options.retry_seconds {
-                    0 => 1,
-                    _ => clone.options.retry_seconds as i64,
-                } * 2)
+            && clone.server_received_times + (clone.options.pong_timeout_seconds.max(1) as i64)
                 > now().timestamp();
         drop(clone);
         result
@@ -282,7 +482,7 @@ impl ServerSenderTrait for Arc<RwLock<ServerSender>> {
         self.write().await.remove_ip();
     }
 
-    async fn remove_ip_if_valid_server_ip(&self, server_ip: &str) {
+    async fn remove_ip_if_valid_server_ip(&self, server_ip: &str) -> Result<()> {
         let db = self.read().await.db.clone();
         let server_connect_info =
             match get_setting_by_key(db.clone(), format!("{:?}", SaveKey::ServerConnectInfo)).await
@@ -294,27 +494,26 @@ impl ServerSenderTrait for Arc<RwLock<ServerSender>> {
                 }
             };
         if let Some(server_connect_info) = server_connect_info {
-            let mut info = ServerConnectInfo::deserialize(&server_connect_info.value).unwrap();
+            let mut info = ServerConnectInfo::deserialize(&server_connect_info.value)?;
 
             if info.server_ip == server_ip {
                 self.remove_ip().await;
-                info.server_ip = "".into();
+                info.server_ip = "";
                 let mut value = Vec::new();
-                info.serialize(&mut value).unwrap();
+                info.serialize(&mut value)?;
                 let db = db.read().await;
-                let writer = db.rw_transaction().unwrap();
-                writer
-                    .update::<Settings>(
-                        server_connect_info,
-                        Settings {
-                            key: format!("{:?}", SaveKey::ServerConnectInfo),
-                            value,
-                        },
-                    )
-                    .unwrap();
-                writer.commit().unwrap();
+                let writer = db.rw_transaction()?;
+                writer.update::<Settings>(
+                    server_connect_info,
+                    Settings {
+                        key: format!("{:?}", SaveKey::ServerConnectInfo),
+                        value,
+                    },
+                )?;
+                writer.commit()?;
             }
         }
+        Ok(())
     }
 
     async fn write_received_times(&self) {
@@ -322,6 +521,32 @@ impl ServerSenderTrait for Arc<RwLock<ServerSender>> {
     }
 }
 
+/// Reconnects a registered client that may still reconnect.
+async fn reconnect_after_send_failure(server_sender: &Arc<RwLock<ServerSender>>) {
+    let reconnect = {
+        let mut clone = server_sender.write().await;
+        match clone.server_sender.clone() {
+            Some(registered) if !clone.is_closed.is_true() && !clone.is_expired => Some((
+                registered,
+                clone.next_reconnect_attempt(),
+                clone.db.clone(),
+                clone.server_ip.copy_string(),
+                clone.options.clone(),
+            )),
+            _ => None,
+        }
+    };
+    if let Some((registered, attempt, db, server_ip, options)) = reconnect {
+        server_sender
+            .send_status(SenderStatus::Reconnecting {
+                attempt,
+                delay: Duration::ZERO,
+            })
+            .await;
+        spawn_internal_websocket(db, registered, server_ip, options).await;
+    }
+}
+
 #[test]
 fn get_sercer_connect_info() {
     let binary: Vec<u8> = vec![0, 0, 0, 0, 5, 0, 0, 0, 49, 54, 50, 53, 48];
@@ -329,3 +554,58 @@ fn get_sercer_connect_info() {
 
     println!("{:?}", data);
 }
+
+#[tokio::test]
+async fn test_send_does_not_hold_the_lock() {
+    use crate::helpers::common::make_pong_message;
+    use tokio::time::timeout;
+
+    let server_sender = Arc::new(RwLock::new(ServerSender::new(
+        crate::test_db(),
+        "".into(),
+        ClientOptions::default(),
+    )));
+    let (sx, mut rx) = mpsc::channel(1);
+    server_sender.write().await.add(sx, "ws://server");
+    server_sender.send(make_pong_message()).await.unwrap();
+
+    let blocked = tokio::spawn({
+        let server_sender = server_sender.clone();
+        async move { server_sender.send(make_pong_message()).await }
+    });
+    tokio::task::yield_now().await;
+    timeout(Duration::from_secs(1), server_sender.write())
+        .await
+        .unwrap()
+        .server_received_times = 1;
+
+    rx.recv().await.unwrap();
+    blocked.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn test_add_saves_explicit_port() {
+    let cases = [
+        ("ws://127.0.0.1:9000", "9000"),
+        ("wss://[::1]:9001", "9001"),
+        ("ws://localhost", ""),
+        ("localhost", ""),
+    ];
+    for (server_ip, port) in cases {
+        let db = crate::test_db();
+        let server_sender = Arc::new(RwLock::new(ServerSender::new(
+            db.clone(),
+            "".into(),
+            ClientOptions::default(),
+        )));
+        let (sx, _rx) = mpsc::channel(1);
+        server_sender.add(sx, server_ip).await.unwrap();
+
+        let saved = get_setting_by_key(db, format!("{:?}", SaveKey::ServerConnectInfo))
+            .await
+            .unwrap()
+            .unwrap();
+        let saved = ServerConnectInfo::deserialize(&saved.value).unwrap();
+        assert_eq!((saved.server_ip, saved.port), (server_ip, port));
+    }
+}
@@ -0,0 +1,344 @@
+#[cfg(any(feature = "native_tls", feature = "rustls"))]
+use rustls_pki_types::CertificateDer;
+#[cfg(any(feature = "native_tls", feature = "rustls"))]
+use sha2::{Digest, Sha256};
+#[cfg(any(feature = "native_tls", feature = "rustls"))]
+use tokio::net::TcpStream;
+#[cfg(any(feature = "native_tls", feature = "rustls"))]
+use tokio_tungstenite::{tungstenite::handshake::client::Request, MaybeTlsStream};
+
+#[cfg(any(feature = "native_tls", feature = "rustls"))]
+use super::error::AtomicWebsocketError;
+use super::{error::Result, internal_server::ServerOptions};
+
+/// A certificate chain and its private key.
+#[cfg(any(feature = "native_tls", feature = "rustls"))]
+#[derive(Clone)]
+pub enum TlsIdentity {
+    /// A DER encoded PKCS#12 archive, only supported by `native_tls`.
+    Pkcs12 { der: Vec<u8>, password: String },
+    /// A PEM certificate chain and a PEM PKCS#8 private key.
+    Pem { cert: Vec<u8>, key: Vec<u8> },
+}
+
+#[cfg(all(feature = "native_tls", not(feature = "rustls")))]
+impl TlsIdentity {
+    fn native_tls_identity(&self) -> Result<native_tls::Identity> {
+        Ok(match self {
+            Self::Pkcs12 { der, password } => native_tls::Identity::from_pkcs12(der, password)?,
+            Self::Pem { cert, key } => native_tls::Identity::from_pkcs8(cert, key)?,
+        })
+    }
+
+    fn acceptor(&self) -> Result<ServerTls> {
+        let acceptor = native_tls::TlsAcceptor::new(self.native_tls_identity()?)?;
+        Ok(ServerTls::NativeTls(acceptor.into()))
+    }
+}
+
+#[cfg(feature = "rustls")]
+impl TlsIdentity {
+    fn rustls_identity(
+        &self,
+    ) -> Result<(
+        Vec<rustls::pki_types::CertificateDer<'static>>,
+        rustls::pki_types::PrivateKeyDer<'static>,
+    )> {
+        use rustls::pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
+
+        let (cert, key) = match self {
+            Self::Pem { cert, key } => (cert, key),
+            Self::Pkcs12 { .. } => {
+                return Err(AtomicWebsocketError::Tls(
+                    "PKCS#12 identities require the native_tls feature".into(),
+                ))
+            }
+        };
+        let certs =
+            CertificateDer::pem_slice_iter(cert).collect::<std::result::Result<Vec<_>, _>>()?;
+        Ok((certs, PrivateKeyDer::from_pem_slice(key)?))
+    }
+
+    fn acceptor(&self) -> Result<ServerTls> {
+        let (certs, key) = self.rustls_identity()?;
+        let config = rustls::ServerConfig::builder_with_provider(crypto_provider())
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_single_cert(certs, key)?;
+        Ok(ServerTls::Rustls(std::sync::Arc::new(config).into()))
+    }
+}
+
+/// A SHA-256 digest the server's leaf certificate has to match.
+#[cfg(any(feature = "native_tls", feature = "rustls"))]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CertificatePin {
+    /// Digest of the DER encoded certificate.
+    Certificate([u8; 32]),
+    /// Digest of the DER encoded SubjectPublicKeyInfo, kept when a certificate
+    /// is renewed with the same key.
+    PublicKey([u8; 32]),
+}
+
+#[cfg(any(feature = "native_tls", feature = "rustls"))]
+impl CertificatePin {
+    pub fn from_certificate(der: &[u8]) -> Self {
+        Self::Certificate(Sha256::digest(der).into())
+    }
+
+    /// `None` when `der` is not an X.509 certificate.
+    pub fn from_certificate_public_key(der: &[u8]) -> Option<Self> {
+        let certificate = CertificateDer::from(der);
+        let certificate = webpki::EndEntityCert::try_from(&certificate).ok()?;
+        let spki = certificate.subject_public_key_info();
+        Some(Self::PublicKey(Sha256::digest(spki).into()))
+    }
+
+    fn matches(&self, der: &[u8]) -> bool {
+        match self {
+            Self::Certificate(_) => Self::from_certificate(der) == *self,
+            Self::PublicKey(_) => Self::from_certificate_public_key(der).as_ref() == Some(self),
+        }
+    }
+}
+
+/// TLS settings of the outer client.
+#[cfg(any(feature = "native_tls", feature = "rustls"))]
+#[derive(Clone)]
+pub struct ClientTlsOptions {
+    /// PEM certificates trusted in addition to the default roots.
+    pub root_certificates: Vec<Vec<u8>>,
+    /// Trusts the system store with `native_tls` and the webpki roots with `rustls`.
+    pub use_default_roots: bool,
+    /// Presented to servers that ask for a client certificate.
+    pub identity: Option<TlsIdentity>,
+    /// The leaf certificate has to match one of these when not empty.
+    pub pins: Vec<CertificatePin>,
+    /// Skips chain and hostname validation, pins are still checked. Only for tests.
+    pub accept_invalid_certs: bool,
+}
+
+#[cfg(any(feature = "native_tls", feature = "rustls"))]
+impl Default for ClientTlsOptions {
+    fn default() -> Self {
+        Self {
+            root_certificates: Vec::new(),
+            use_default_roots: true,
+            identity: None,
+            pins: Vec::new(),
+            accept_invalid_certs: false,
+        }
+    }
+}
+
+#[cfg(any(feature = "native_tls", feature = "rustls"))]
+impl ClientTlsOptions {
+    /// Opens the TCP connection for `request` and runs the TLS handshake, so
+    /// pins are checked before anything else is sent.
+    pub(crate) async fn connect(&self, request: &Request) -> Result<MaybeTlsStream<TcpStream>> {
+        let uri = request.uri();
+        let host = uri
+            .host()
+            .ok_or_else(|| AtomicWebsocketError::Handshake(format!("no host in {}", uri)))?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let stream = TcpStream::connect((host, uri.port_u16().unwrap_or(443))).await?;
+        self.handshake(host, stream).await
+    }
+
+    fn check_pins(&self, certificate: Option<&[u8]>) -> Result<()> {
+        if self.pins.is_empty() {
+            return Ok(());
+        }
+        let certificate = certificate
+            .ok_or_else(|| AtomicWebsocketError::Tls("server sent no certificate".into()))?;
+        match self.pins.iter().any(|pin| pin.matches(certificate)) {
+            true => Ok(()),
+            false => Err(AtomicWebsocketError::Tls(
+                "server certificate does not match any pin".into(),
+            )),
+        }
+    }
+}
+
+#[cfg(all(feature = "native_tls", not(feature = "rustls")))]
+impl ClientTlsOptions {
+    async fn handshake(&self, host: &str, stream: TcpStream) -> Result<MaybeTlsStream<TcpStream>> {
+        let mut builder = native_tls::TlsConnector::builder();
+        builder
+            .disable_built_in_roots(!self.use_default_roots)
+            .danger_accept_invalid_certs(self.accept_invalid_certs)
+            .danger_accept_invalid_hostnames(self.accept_invalid_certs);
+        for cert in &self.root_certificates {
+            builder.add_root_certificate(native_tls::Certificate::from_pem(cert)?);
+        }
+        if let Some(identity) = &self.identity {
+            builder.identity(identity.native_tls_identity()?);
+        }
+        let connector = tokio_native_tls::TlsConnector::from(builder.build()?);
+        let stream = connector.connect(host, stream).await?;
+        let certificate = match stream.get_ref().peer_certificate()? {
+            Some(certificate) => Some(certificate.to_der()?),
+            None => None,
+        };
+        self.check_pins(certificate.as_deref())?;
+        Ok(MaybeTlsStream::NativeTls(stream))
+    }
+}
+
+#[cfg(feature = "rustls")]
+impl ClientTlsOptions {
+    async fn handshake(&self, host: &str, stream: TcpStream) -> Result<MaybeTlsStream<TcpStream>> {
+        use rustls::pki_types::ServerName;
+
+        let server_name = ServerName::try_from(host.to_string())
+            .map_err(|e| AtomicWebsocketError::Tls(e.to_string()))?;
+        let connector =
+            tokio_rustls::TlsConnector::from(std::sync::Arc::new(self.rustls_config()?));
+        let stream = connector
+            .connect(server_name, stream)
+            .await
+            .map_err(|error| match error.get_ref() {
+                // Certificate failures arrive wrapped in io errors.
+                Some(inner) if inner.is::<rustls::Error>() => {
+                    AtomicWebsocketError::Tls(inner.to_string())
+                }
+                _ => error.into(),
+            })?;
+        let certificate = stream
+            .get_ref()
+            .1
+            .peer_certificates()
+            .and_then(|certificates| certificates.first());
+        self.check_pins(certificate.map(|certificate| certificate.as_ref()))?;
+        Ok(MaybeTlsStream::Rustls(stream))
+    }
+
+    fn rustls_config(&self) -> Result<rustls::ClientConfig> {
+        use rustls::pki_types::{pem::PemObject, CertificateDer};
+
+        let provider = crypto_provider();
+        let builder = rustls::ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?;
+        let builder = if self.accept_invalid_certs {
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(std::sync::Arc::new(AcceptInvalidCerts(provider)))
+        } else {
+            let mut roots = rustls::RootCertStore::empty();
+            if self.use_default_roots {
+                roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+            }
+            for cert in &self.root_certificates {
+                for cert in CertificateDer::pem_slice_iter(cert) {
+                    roots.add(cert?)?;
+                }
+            }
+            builder.with_root_certificates(roots)
+        };
+        Ok(match &self.identity {
+            Some(identity) => {
+                let (certs, key) = identity.rustls_identity()?;
+                builder.with_client_auth_cert(certs, key)?
+            }
+            None => builder.with_no_client_auth(),
+        })
+    }
+}
+
+/// Explicit so another crate enabling a second provider does not make the default ambiguous.
+#[cfg(feature = "rustls")]
+fn crypto_provider() -> std::sync::Arc<rustls::crypto::CryptoProvider> {
+    std::sync::Arc::new(rustls::crypto::ring::default_provider())
+}
+
+/// Still checks handshake signatures, only the certificate itself is trusted blindly.
+#[cfg(feature = "rustls")]
+#[derive(Debug)]
+struct AcceptInvalidCerts(std::sync::Arc<rustls::crypto::CryptoProvider>);
+
+#[cfg(feature = "rustls")]
+impl rustls::client::danger::ServerCertVerifier for AcceptInvalidCerts {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &rustls::pki_types::CertificateDer<'_>,
+        _intermediates: &[rustls::pki_types::CertificateDer<'_>],
+        _server_name: &rustls::pki_types::ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: rustls::pki_types::UnixTime,
+    ) -> std::result::Result<rustls::client::danger::ServerCertVerified, rustls::Error> {
+        Ok(rustls::client::danger::ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> std::result::Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> std::result::Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// TLS acceptor built once from `ServerOptions`.
+#[derive(Clone)]
+pub(crate) enum ServerTls {
+    Plain,
+    #[cfg(all(feature = "native_tls", not(feature = "rustls")))]
+    NativeTls(tokio_native_tls::TlsAcceptor),
+    #[cfg(feature = "rustls")]
+    Rustls(tokio_rustls::TlsAcceptor),
+}
+
+impl ServerTls {
+    pub fn new(option: &ServerOptions) -> Result<Self> {
+        #[cfg(any(feature = "native_tls", feature = "rustls"))]
+        if let Some(identity) = &option.tls_identity {
+            return identity.acceptor();
+        }
+        #[cfg(not(any(feature = "native_tls", feature = "rustls")))]
+        let _ = option;
+        Ok(Self::Plain)
+    }
+}
+
+#[cfg(any(feature = "native_tls", feature = "rustls"))]
+#[test]
+fn test_certificate_public_key_pin() {
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+    let public_key = certified.key_pair.public_key_der();
+    assert_eq!(
+        CertificatePin::from_certificate_public_key(certified.cert.der()),
+        Some(CertificatePin::PublicKey(
+            Sha256::digest(&public_key).into()
+        ))
+    );
+    let truncated = &certified.cert.der()[..certified.cert.der().len() - 1];
+    assert_eq!(CertificatePin::from_certificate_public_key(truncated), None);
+    assert_eq!(
+        CertificatePin::from_certificate_public_key(b"not a certificate"),
+        None
+    );
+}
@@ -0,0 +1,81 @@
+use std::sync::Arc;
+
+use tokio_tungstenite::tungstenite::{
+    handshake::server::Request,
+    http::{
+        header::{ORIGIN, SEC_WEBSOCKET_PROTOCOL},
+        HeaderMap, StatusCode,
+    },
+};
+
+type FilterFn = dyn Fn(&UpgradeRequest) -> Result<(), StatusCode> + Send + Sync;
+
+/// The HTTP upgrade request a session was opened with.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct UpgradeRequest {
+    pub path: String,
+    pub query: Option<String>,
+    pub headers: HeaderMap,
+    /// Offered in `Sec-WebSocket-Protocol`, in the client's order.
+    pub subprotocols: Vec<String>,
+    /// The one picked from `ServerOptions::subprotocols` and echoed in the response.
+    pub subprotocol: Option<String>,
+}
+
+impl UpgradeRequest {
+    pub(crate) fn new(request: &Request) -> Self {
+        let subprotocols = request
+            .headers()
+            .get_all(SEC_WEBSOCKET_PROTOCOL)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(|subprotocol| subprotocol.trim().to_string())
+            .filter(|subprotocol| !subprotocol.is_empty())
+            .collect();
+        Self {
+            path: request.uri().path().into(),
+            query: request.uri().query().map(String::from),
+            headers: request.headers().clone(),
+            subprotocols,
+            subprotocol: None,
+        }
+    }
+
+    pub fn origin(&self) -> Option<&str> {
+        self.headers
+            .get(ORIGIN)
+            .and_then(|value| value.to_str().ok())
+    }
+
+    /// Picks the first of `supported` the client offered, so the server's order wins.
+    pub(crate) fn negotiate(&mut self, supported: &[String]) {
+        self.subprotocol = supported
+            .iter()
+            .find(|subprotocol| self.subprotocols.contains(subprotocol))
+            .cloned();
+    }
+}
+
+/// Accepts or refuses an upgrade before the WebSocket session starts.
+/// Runs inside the HTTP handshake, so it can not await.
+#[derive(Clone)]
+pub struct UpgradeFilter {
+    handler: Arc<FilterFn>,
+}
+
+impl UpgradeFilter {
+    pub fn new<F>(handler: F) -> Self
+    where
+        F: Fn(&UpgradeRequest) -> Result<(), StatusCode> + Send + Sync + 'static,
+    {
+        Self {
+            handler: Arc::new(handler),
+        }
+    }
+
+    /// `Err` answers the upgrade with that status instead of `101`.
+    pub fn check(&self, request: &UpgradeRequest) -> Result<(), StatusCode> {
+        (self.handler)(request)
+    }
+}
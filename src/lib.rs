@@ -1,7 +1,9 @@
-use std::sync::Arc;
+use std::sync::{atomic::AtomicBool, Arc};
 
 use client_sender::ClientSenders;
+use error::Result;
 use helpers::{
+    endpoints::Endpoints,
     internal_client::{AtomicClient, ClientOptions},
     internal_server::{AtomicServer, ServerOptions},
 };
@@ -12,11 +14,14 @@ use serde::{Deserialize, Serialize};
 pub mod external {
     pub use async_trait;
     pub use futures_util;
+    pub use ipnet;
     pub use nanoid;
     pub use native_db;
     pub use native_model;
     #[cfg(feature = "native_tls")]
     pub use native_tls;
+    #[cfg(feature = "rustls")]
+    pub use rustls;
     pub use tokio;
     pub use tokio_tungstenite;
 }
@@ -28,18 +33,54 @@ pub mod schema {
 pub mod client_sender {
     pub use crate::helpers::client_sender::*;
     pub use crate::helpers::internal_server::ServerOptions;
+    #[cfg(any(feature = "native_tls", feature = "rustls"))]
+    pub use crate::helpers::tls::TlsIdentity;
 }
 
 pub mod server_sender {
+    pub use crate::helpers::client_sender::DisconnectReason;
+    pub use crate::helpers::endpoints::EndpointHealth;
     pub use crate::helpers::internal_client::{
-        get_internal_connect, get_ip_address, ClientOptions,
+        get_internal_connect, get_ip_address, AtomicClient, ClientOptions,
     };
+    pub use crate::helpers::network::{local_ip_address, local_networks, LocalNetwork};
+    pub use crate::helpers::scan_manager::ScanOptions;
     pub use crate::helpers::server_sender::*;
+    #[cfg(any(feature = "native_tls", feature = "rustls"))]
+    pub use crate::helpers::tls::{CertificatePin, ClientTlsOptions, TlsIdentity};
+}
+
+pub mod auth {
+    pub use crate::helpers::auth::*;
+}
+
+pub mod upgrade {
+    pub use crate::helpers::upgrade::*;
+}
+
+pub mod discovery {
+    pub use crate::helpers::discovery::{discover, Announcement, DiscoveryOptions};
+}
+
+pub mod channel {
+    pub use crate::helpers::channel::{
+        BackpressurePolicy, ChannelOptions, DroppedMessages, PolicySender,
+    };
+}
+
+pub mod router {
+    pub use crate::helpers::router::*;
+}
+
+pub mod error {
+    pub use crate::helpers::error::*;
 }
 
 pub mod common {
     pub use crate::helpers::common::{
-        get_setting_by_key, make_atomic_message, make_response_message, set_setting,
+        get_rpc_request, get_setting_by_key, make_atomic_message, make_response_message,
+        make_rpc_error_message, make_rpc_request_message, make_rpc_response_message,
+        remove_setting_by_key, set_setting,
     };
     pub use crate::helpers::get_internal_websocket::get_id;
 }
@@ -70,14 +111,14 @@ impl AtomicWebsocket {
     pub async fn get_internal_client(
         db: Arc<RwLock<Database<'static>>>,
         options: ClientOptions,
-    ) -> AtomicClient {
+    ) -> Result<AtomicClient> {
         get_client(db, options, AtomicWebsocketType::Internal, None).await
     }
     pub async fn get_internal_client_with_server_sender(
         db: Arc<RwLock<Database<'static>>>,
         options: ClientOptions,
         server_sender: Arc<RwLock<ServerSender>>,
-    ) -> AtomicClient {
+    ) -> Result<AtomicClient> {
         get_client(
             db,
             options,
@@ -90,7 +131,7 @@ impl AtomicWebsocket {
     pub async fn get_outer_client(
         db: Arc<RwLock<Database<'static>>>,
         options: ClientOptions,
-    ) -> AtomicClient {
+    ) -> Result<AtomicClient> {
         get_client(db, options, AtomicWebsocketType::Outer, None).await
     }
 
@@ -98,11 +139,11 @@ impl AtomicWebsocket {
         db: Arc<RwLock<Database<'static>>>,
         options: ClientOptions,
         server_sender: Arc<RwLock<ServerSender>>,
-    ) -> AtomicClient {
+    ) -> Result<AtomicClient> {
         get_client(db, options, AtomicWebsocketType::Outer, Some(server_sender)).await
     }
 
-    pub async fn get_internal_server(addr: String, option: ServerOptions) -> AtomicServer {
+    pub async fn get_internal_server(addr: String, option: ServerOptions) -> Result<AtomicServer> {
         AtomicServer::new(&addr, option, None).await
     }
 
@@ -110,7 +151,7 @@ impl AtomicWebsocket {
         addr: String,
         option: ServerOptions,
         client_senders: Arc<RwLock<ClientSenders>>,
-    ) -> AtomicServer {
+    ) -> Result<AtomicServer> {
         AtomicServer::new(&addr, option, Some(client_senders)).await
     }
 }
@@ -120,13 +161,16 @@ async fn get_client(
     options: ClientOptions,
     atomic_websocket_type: AtomicWebsocketType,
     server_sender: Option<Arc<RwLock<ServerSender>>>,
-) -> AtomicClient {
+) -> Result<AtomicClient> {
     let mut server_sender = match server_sender {
         Some(server_sender) => {
             let server_sender_clone = server_sender.clone();
             let mut server_sender_clone = server_sender_clone.write().await;
             server_sender_clone.server_ip = options.url.clone();
+            server_sender_clone.endpoints = Endpoints::new(options.outer_urls());
             server_sender_clone.options = options.clone();
+            server_sender_clone.is_closed = Arc::new(AtomicBool::new(false));
+            server_sender_clone.generation += 1;
             drop(server_sender_clone);
             server_sender
         }
@@ -138,13 +182,26 @@ async fn get_client(
     };
     server_sender.regist(server_sender.clone()).await;
 
-    let atomic_websocket: AtomicClient = AtomicClient {
-        server_sender,
-        options,
-    };
+    let atomic_websocket = AtomicClient::new(server_sender, options).await;
     match atomic_websocket_type {
-        AtomicWebsocketType::Internal => atomic_websocket.internal_initialize(db.clone()).await,
-        AtomicWebsocketType::Outer => atomic_websocket.outer_initialize(db.clone()).await,
+        AtomicWebsocketType::Internal => atomic_websocket.internal_initialize(db.clone()).await?,
+        AtomicWebsocketType::Outer => atomic_websocket.outer_initialize(db.clone()).await?,
     }
-    atomic_websocket
+    Ok(atomic_websocket)
+}
+
+#[cfg(test)]
+pub(crate) fn test_db() -> Arc<RwLock<Database<'static>>> {
+    use native_db::{Builder, Models};
+    use std::sync::OnceLock;
+
+    static MODELS: OnceLock<Models> = OnceLock::new();
+    let models = MODELS.get_or_init(|| {
+        let mut models = Models::new();
+        models.define::<Settings>().unwrap();
+        models
+    });
+    Arc::new(RwLock::new(
+        Builder::new().create_in_memory(models).unwrap(),
+    ))
 }
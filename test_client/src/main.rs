@@ -10,7 +10,7 @@ use atomic_websocket::{
     common::{get_id, make_response_message},
     external::native_db::{Builder, Database, Models},
     schema::{AppStartup, AppStartupOutput, Category, Data, ServerConnectInfo},
-    server_sender::{ClientOptions, SenderStatus, ServerSender, ServerSenderTrait},
+    server_sender::{AtomicClient, ClientOptions, SenderStatus, ServerSender, ServerSenderTrait},
     AtomicWebsocket, Settings,
 };
 use bebop::Record;
@@ -28,19 +28,25 @@ async fn main() {
     log4rs::init_raw_config(config).unwrap();
 
     let port = "9000";
-    tokio::spawn(internal_client_start(port));
+    // The client stops its background tasks when dropped, so keep it alive here.
+    let _atomic_client = internal_client_start(port).await;
 
-    // tokio::spawn(outer_client_start());
+    // let _outer_client = outer_client_start().await;
 
     loop {
         sleep(Duration::from_secs(100)).await;
     }
 }
 
-async fn outer_client_start() {
-    let mut client_options = ClientOptions::default();
-    client_options.url = "example.com/websocket".into();
-    let atomic_client = AtomicWebsocket::get_outer_client(db().clone(), client_options).await;
+#[allow(dead_code)]
+async fn outer_client_start() -> AtomicClient {
+    let client_options = ClientOptions {
+        url: "example.com/websocket".into(),
+        ..Default::default()
+    };
+    let atomic_client = AtomicWebsocket::get_outer_client(db().clone(), client_options)
+        .await
+        .unwrap();
 
     let status_receiver = atomic_client.get_status_receiver().await;
     let handle_message_receiver = atomic_client.get_handle_message_receiver().await;
@@ -49,18 +55,24 @@ async fn outer_client_start() {
     tokio::spawn(receive_handle_message(handle_message_receiver));
 
     let _ = atomic_client.get_outer_connect(db().clone()).await;
+    atomic_client
 }
 
-async fn internal_client_start(port: &str) {
-    let mut client_options = ClientOptions::default();
-    client_options.retry_seconds = 2;
-    client_options.use_keep_ip = true;
+async fn internal_client_start(port: &str) -> AtomicClient {
+    let client_options = ClientOptions {
+        retry_seconds: 2,
+        ping_interval_seconds: 2,
+        pong_timeout_seconds: 6,
+        use_keep_ip: true,
+        ..Default::default()
+    };
     let atomic_client = AtomicWebsocket::get_internal_client_with_server_sender(
         db().clone(),
         client_options,
         server_sender().clone(),
     )
-    .await;
+    .await
+    .unwrap();
 
     let status_receiver = atomic_client.get_status_receiver().await;
     let handle_message_receiver = atomic_client.get_handle_message_receiver().await;
@@ -77,17 +89,18 @@ async fn internal_client_start(port: &str) {
             db().clone(),
         )
         .await;
+    atomic_client
 }
 
 pub async fn receive_status(mut receiver: Receiver<SenderStatus>) {
     while let Some(status) = receiver.recv().await {
         log::debug!("Status: {:?}", status);
-        if status == SenderStatus::Disconnected {
-            log::debug!("Disconnected");
+        if let SenderStatus::Disconnected { reason } = &status {
+            log::debug!("Disconnected: {:?}", reason);
         }
         if status == SenderStatus::Connected {
             log::debug!("Connected");
-            let id = get_id(db().clone()).await;
+            let id = get_id(db().clone()).await.unwrap();
             let mut datas = vec![];
             AppStartup {
                 id: &id,
@@ -95,12 +108,15 @@ pub async fn receive_status(mut receiver: Receiver<SenderStatus>) {
             }
             .serialize(&mut datas)
             .unwrap();
-            server_sender()
+            if let Err(error) = server_sender()
                 .send(make_response_message(
                     atomic_websocket::schema::Category::AppStartup,
                     datas,
                 ))
-                .await;
+                .await
+            {
+                log::error!("Failed to send AppStartup: {:?}", error);
+            }
         }
     }
 }
@@ -116,7 +132,7 @@ pub async fn receive_handle_message(mut receiver: Receiver<Vec<u8>>) {
                         AppStartupOutput::deserialize(&data.datas).unwrap()
                     );
                     sleep(Duration::from_secs(2)).await;
-                    let id = get_id(db().clone()).await;
+                    let id = get_id(db().clone()).await.unwrap();
                     let mut datas = vec![];
                     AppStartup {
                         id: &id,
@@ -124,12 +140,15 @@ pub async fn receive_handle_message(mut receiver: Receiver<Vec<u8>>) {
                     }
                     .serialize(&mut datas)
                     .unwrap();
-                    server_sender()
+                    if let Err(error) = server_sender()
                         .send(make_response_message(
                             atomic_websocket::schema::Category::AppStartup,
                             datas,
                         ))
-                        .await;
+                        .await
+                    {
+                        log::error!("Failed to send AppStartup: {:?}", error);
+                    }
                 }
                 _ => {
                     log::debug!("Unknown category: {:?}", data);
@@ -140,7 +159,7 @@ pub async fn receive_handle_message(mut receiver: Receiver<Vec<u8>>) {
 }
 
 pub fn get_db_path() -> Result<PathBuf, Box<dyn Error>> {
-    let mut current_path = PathBuf::from(current_dir().unwrap());
+    let mut current_path = current_dir().unwrap();
     current_path.push("database.redb");
     Ok(current_path)
 }
@@ -149,7 +168,7 @@ pub fn make_models() -> &'static Models {
     static BUILDER: OnceLock<Models> = OnceLock::new();
     BUILDER.get_or_init(|| {
         let mut models = Models::new();
-        if let Err(_) = models.define::<Settings>() {
+        if models.define::<Settings>().is_err() {
             log::error!("Failed to define ClientTable");
         };
         models
@@ -161,7 +180,7 @@ pub fn db() -> &'static Arc<RwLock<Database<'static>>> {
     BUILDER.get_or_init(|| {
         Arc::new(RwLock::new(
             Builder::new()
-                .create(&make_models(), get_db_path().unwrap())
+                .create(make_models(), get_db_path().unwrap())
                 .unwrap(),
         ))
     })
@@ -10,14 +10,12 @@ use atomic_websocket::{
     client_sender::{ClientSenders, ClientSendersTrait, ServerOptions},
     common::make_response_message,
     external::native_db::{Builder, Database, Models},
-    schema::{AppStartup, AppStartupOutput, Category, Data},
+    router::{RouteMessage, Router},
+    schema::{AppStartup, AppStartupOutput, Category},
     AtomicWebsocket, Settings,
 };
 use bebop::Record;
-use tokio::{
-    sync::{mpsc::Receiver, RwLock},
-    time::sleep,
-};
+use tokio::{sync::RwLock, time::sleep};
 
 #[tokio::main]
 async fn main() {
@@ -46,45 +44,47 @@ async fn server_start(address: String) {
         option,
         client_senders().clone(),
     )
-    .await;
-    let handle_message_receiver = atomic_server.get_handle_message_receiver().await;
+    .await
+    .unwrap();
+    log::debug!("Server listening on {}", atomic_server.local_addr());
+    let router = Router::new()
+        .route(Category::AppStartup as u16, handle_app_startup)
+        .fallback(|message| async move {
+            log::debug!(
+                "peer: {} Unknown category: {:?}",
+                message.peer,
+                message.category
+            );
+        });
 
-    tokio::spawn(receive_server_handle_message(handle_message_receiver));
+    // Keep the server alive for as long as messages are handled.
+    let _ = atomic_server.route(router).await.await;
+    atomic_server.shutdown(Duration::from_secs(5)).await;
 }
 
-pub async fn receive_server_handle_message(mut receiver: Receiver<(Vec<u8>, String)>) {
-    while let Some((data, peer)) = receiver.recv().await {
-        // log::debug!("Message: {:?}", message);
-
-        if let Ok(data) = Data::deserialize(&data) {
-            match Category::try_from(data.category as u32).unwrap() {
-                Category::AppStartup => {
-                    log::debug!("peer: {} {:?}", peer, data);
-                    log::debug!(
-                        "AppStartup: {:?}",
-                        AppStartup::deserialize(&data.datas).unwrap()
-                    );
-                    let mut datas = vec![];
-                    AppStartupOutput { success: true }
-                        .serialize(&mut datas)
-                        .unwrap();
-                    client_senders()
-                        .send(
-                            &peer,
-                            make_response_message(Category::AppStartupOutput, datas),
-                        )
-                        .await;
-                }
-                _ => {
-                    log::debug!("peer: {} Unknown category: {:?}", peer, data);
-                }
-            }
-        }
+async fn handle_app_startup(message: RouteMessage) {
+    log::debug!(
+        "peer: {} AppStartup: {:?}",
+        message.peer,
+        AppStartup::deserialize(&message.datas)
+    );
+    let mut datas = vec![];
+    AppStartupOutput { success: true }
+        .serialize(&mut datas)
+        .unwrap();
+    if let Err(error) = client_senders()
+        .send(
+            &message.peer,
+            make_response_message(Category::AppStartupOutput, datas),
+        )
+        .await
+    {
+        log::error!("Failed to send AppStartupOutput: {:?}", error);
     }
 }
 
 pub fn get_db_path() -> Result<PathBuf, Box<dyn Error>> {
-    let mut current_path = PathBuf::from(current_dir().unwrap());
+    let mut current_path = current_dir().unwrap();
     current_path.push("database.redb");
     Ok(current_path)
 }
@@ -93,7 +93,7 @@ pub fn make_models() -> &'static Models {
     static BUILDER: OnceLock<Models> = OnceLock::new();
     BUILDER.get_or_init(|| {
         let mut models = Models::new();
-        if let Err(_) = models.define::<Settings>() {
+        if models.define::<Settings>().is_err() {
             log::error!("Failed to define ClientTable");
         };
         models
@@ -105,7 +105,7 @@ pub fn db() -> &'static Arc<RwLock<Database<'static>>> {
     BUILDER.get_or_init(|| {
         Arc::new(RwLock::new(
             Builder::new()
-                .create(&make_models(), get_db_path().unwrap())
+                .create(make_models(), get_db_path().unwrap())
                 .unwrap(),
         ))
     })